@@ -1,9 +1,6 @@
 use mqtt_channel_client::{
-    paho_mqtt::{
-        connect_options::ConnectOptionsBuilder, create_options::CreateOptionsBuilder, Message,
-        PersistenceType,
-    },
-    Client, ClientConfigBuilder, Event, SubscriptionBuilder,
+    paho_mqtt::{create_options::CreateOptionsBuilder, PersistenceType},
+    Client, ClientConfigBuilder, ConnectOptionsBuilder, Event, Message, SubscriptionBuilder,
 };
 use prometheus_client::{encoding::text::encode, registry::Registry};
 use std::time::Duration;
@@ -68,13 +65,14 @@ async fn main() {
     // Connect to the broker
     client
         .start(
-            ConnectOptionsBuilder::new()
+            ConnectOptionsBuilder::default()
                 .clean_session(true)
                 .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(5))
-                .keep_alive_interval(Duration::from_secs(5))
+                .keep_alive(Duration::from_secs(5))
                 .user_name("me")
                 .password("my_password")
-                .finalize(),
+                .build()
+                .unwrap(),
         )
         .await
         .unwrap();
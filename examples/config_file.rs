@@ -0,0 +1,43 @@
+use mqtt_channel_client::{Event, Settings};
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    // Load settings from the file given as the first argument, overridden by MQTT_* environment
+    // variables
+    let settings = match std::env::args().nth(1) {
+        Some(path) => Settings::load(path),
+        None => Settings::from_env(),
+    }
+    .unwrap();
+
+    // Create the client, with the subscriptions from the settings
+    let client = settings.client().unwrap();
+
+    // Print received messages
+    let mut rx = client.rx_channel();
+    let print_task = tokio::spawn(async move {
+        loop {
+            if let Ok(Event::Rx(msg)) = rx.recv().await {
+                println!("{}: {}", msg.topic(), msg.payload_str());
+            }
+        }
+    });
+
+    // Connect to the broker
+    client
+        .start(settings.connect_options().unwrap())
+        .await
+        .unwrap();
+
+    // Wait for an exit signal
+    tokio::signal::ctrl_c().await.unwrap();
+    println!("Exiting...");
+
+    // Disconnect from the broker
+    client.stop().await.unwrap();
+
+    // Exit tasks
+    print_task.abort();
+}
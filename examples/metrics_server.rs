@@ -0,0 +1,70 @@
+use mqtt_channel_client::{
+    paho_mqtt::{create_options::CreateOptionsBuilder, PersistenceType},
+    Client, ClientConfigBuilder, ConnectOptionsBuilder, Event, Message, SubscriptionBuilder,
+};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    // Create the client, serving metrics on http://localhost:9090/metrics
+    let client = Client::new(
+        CreateOptionsBuilder::new()
+            .server_uri("tcp://localhost:1883")
+            .client_id("demo")
+            .persistence(PersistenceType::None)
+            .finalize(),
+        ClientConfigBuilder::default()
+            .metrics_server_address(Some("127.0.0.1:9090".parse().unwrap()))
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+
+    // Start a task to reply to pings
+    let tx = client.tx_channel();
+    let mut rx = client.rx_channel();
+    let pong_task = tokio::spawn(async move {
+        loop {
+            if let Ok(Event::Rx(msg)) = rx.recv().await {
+                if msg.topic().starts_with("ping/") {
+                    let topic = format!("pong/{}", msg.topic().strip_prefix("ping/").unwrap());
+                    tx.send(Event::Tx(Message::new(topic, msg.payload(), msg.qos())))
+                        .unwrap();
+                }
+            }
+        }
+    });
+
+    // Add a subscription
+    client.subscribe(
+        SubscriptionBuilder::default()
+            .topic("ping/+".into())
+            .build()
+            .unwrap(),
+    );
+
+    // Connect to the broker, this also starts the metrics server
+    client
+        .start(
+            ConnectOptionsBuilder::default()
+                .clean_session(true)
+                .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(5))
+                .keep_alive(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Wait for an exit signal
+    tokio::signal::ctrl_c().await.unwrap();
+    println!("Exiting...");
+
+    // Disconnect from the broker, this also stops the metrics server
+    client.stop().await.unwrap();
+
+    // Exit tasks
+    pong_task.abort();
+}
@@ -0,0 +1,82 @@
+use mqtt_channel_client::{
+    paho_mqtt::{create_options::CreateOptionsBuilder, PersistenceType},
+    Client, ClientConfig, ConnectOptionsBuilder, Responder, SubscriptionBuilder, MQTT_VERSION_5,
+};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    // Create the client
+    let client = Client::new(
+        CreateOptionsBuilder::new()
+            .server_uri("tcp://localhost:1883")
+            .client_id("demo")
+            .mqtt_version(MQTT_VERSION_5)
+            .persistence(PersistenceType::None)
+            .finalize(),
+        ClientConfig::default(),
+    )
+    .unwrap();
+
+    // Answer requests by reversing the payload
+    let responder = Responder::new(
+        &client,
+        SubscriptionBuilder::default()
+            .topic("service/reverse".into())
+            .qos_at_least_once()
+            .build()
+            .unwrap(),
+        |msg| {
+            let mut payload = msg.payload().to_vec();
+            payload.reverse();
+            Some(payload)
+        },
+    );
+
+    // Connect to the broker
+    client
+        .start(
+            ConnectOptionsBuilder::default()
+                .mqtt_version(MQTT_VERSION_5)
+                .clean_session(true)
+                .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(5))
+                .keep_alive(Duration::from_secs(5))
+                .user_name("me")
+                .password("my_password")
+                .build()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Start a task to make requests
+    let c2 = client.clone();
+    let request_task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+
+        loop {
+            interval.tick().await;
+
+            match c2
+                .request("service/reverse", "hello", Duration::from_secs(2))
+                .await
+            {
+                Ok(msg) => println!("Response: {}", msg.payload_str()),
+                Err(e) => println!("Request failed: {}", e),
+            }
+        }
+    });
+
+    // Wait for an exit signal
+    tokio::signal::ctrl_c().await.unwrap();
+    println!("Exiting...");
+
+    // Disconnect from the broker
+    client.stop().await.unwrap();
+
+    // Exit tasks
+    request_task.abort();
+    responder.stop();
+}
@@ -0,0 +1,730 @@
+mod codec;
+
+use self::codec::{Packet, Publication, Publish};
+use crate::topic;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+
+/// Minimal in-process MQTT broker for testing a [`Client`](crate::Client) end-to-end.
+///
+/// The broker listens on an ephemeral port on localhost and supports MQTT v3.1.1 and v5 clients,
+/// QoS 0, 1 and 2, wildcard and shared subscriptions, retained messages and will messages.
+///
+/// It is not a complete broker: credentials are not checked, sessions are not persisted across
+/// connections, messages are not redelivered and limits such as keep alive are not enforced.
+///
+/// The broker stops when it is dropped.
+pub struct LoopbackBroker {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    handle: JoinHandle<()>,
+}
+
+impl LoopbackBroker {
+    /// Start a broker listening on an ephemeral port on localhost.
+    pub async fn start() -> crate::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+
+        let state: Arc<Mutex<State>> = Default::default();
+
+        let handle = tokio::spawn({
+            let state = state.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(connection(state.clone(), stream));
+                        }
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Failed to accept connection");
+                        }
+                    }
+                }
+            }
+        });
+
+        tracing::debug!(address = %address, "Loopback broker started");
+
+        Ok(Self {
+            address,
+            state,
+            handle,
+        })
+    }
+
+    /// Get the address the broker is listening on.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Get the URI clients connect to the broker with.
+    pub fn uri(&self) -> String {
+        format!("tcp://{}", self.address)
+    }
+
+    /// Get the IDs of the connected clients.
+    pub fn client_ids(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .sessions
+            .values()
+            .map(|s| s.client_id.clone())
+            .collect()
+    }
+
+    /// Get the topics that have a retained message.
+    pub fn retained_topics(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .retained
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Close the connection of a client as if it was lost, publishing its will message.
+    ///
+    /// Returns `false` if no client with the ID is connected.
+    pub fn drop_client(&self, client_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.find_session(client_id) {
+            Some(id) => {
+                state.disconnect(id, true);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for LoopbackBroker {
+    fn drop(&mut self) {
+        self.handle.abort();
+
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<u64> = state.sessions.keys().copied().collect();
+        for id in ids {
+            state.disconnect(id, false);
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_connection: u64,
+    sessions: HashMap<u64, Session>,
+    retained: BTreeMap<String, Publication>,
+
+    /// Counter used to distribute messages between the members of shared subscriptions.
+    next_shared: usize,
+}
+
+struct Session {
+    client_id: String,
+    version: u8,
+    will: Option<Publication>,
+    subscriptions: Vec<SessionSubscription>,
+
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    close: Arc<Notify>,
+
+    next_packet_id: u16,
+    topic_aliases: HashMap<u16, String>,
+    awaiting_release: HashSet<u16>,
+}
+
+#[derive(Clone)]
+struct SessionSubscription {
+    filter: String,
+    qos: u8,
+    no_local: bool,
+    retain_as_published: bool,
+    subscription_id: Option<u32>,
+}
+
+impl SessionSubscription {
+    fn is_shared(&self) -> bool {
+        self.filter.starts_with("$share/")
+    }
+}
+
+impl Session {
+    fn send(&self, packet: Vec<u8>) {
+        // The connection may already be closing, in which case the packet is not needed
+        let _ = self.tx.send(packet);
+    }
+
+    fn publish(&mut self, publication: &Publication, qos: u8, retain: bool, ids: &[u32]) {
+        let packet_id = (qos > 0).then(|| {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            self.next_packet_id
+        });
+
+        self.send(codec::publish(
+            self.version,
+            packet_id,
+            publication,
+            qos,
+            retain,
+            ids,
+        ));
+    }
+}
+
+impl State {
+    fn find_session(&self, client_id: &str) -> Option<u64> {
+        self.sessions
+            .iter()
+            .find(|(_, s)| s.client_id == client_id)
+            .map(|(id, _)| *id)
+    }
+
+    /// Add the session of a newly connected client, returning its ID.
+    fn connect(
+        &mut self,
+        connect: codec::Connect,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        close: Arc<Notify>,
+    ) -> u64 {
+        let id = self.next_connection;
+        self.next_connection += 1;
+
+        let assigned = connect.client_id.is_empty();
+        let client_id = if assigned {
+            format!("loopback-{}", id)
+        } else {
+            connect.client_id
+        };
+
+        // A new connection takes over the session of an existing client with the same ID
+        if let Some(existing) = self.find_session(&client_id) {
+            self.disconnect(existing, true);
+        }
+
+        tracing::debug!(client_id, version = connect.version, "Client connected");
+
+        let session = Session {
+            client_id,
+            version: connect.version,
+            will: connect.will,
+            subscriptions: Vec::new(),
+
+            tx,
+            close,
+
+            next_packet_id: 0,
+            topic_aliases: HashMap::new(),
+            awaiting_release: HashSet::new(),
+        };
+
+        session.send(codec::connack(
+            session.version,
+            0,
+            assigned.then_some(session.client_id.as_str()),
+        ));
+
+        self.sessions.insert(id, session);
+        id
+    }
+
+    /// Remove a session and close its connection, optionally publishing its will message.
+    fn disconnect(&mut self, id: u64, publish_will: bool) {
+        if let Some(session) = self.sessions.remove(&id) {
+            tracing::debug!(client_id = session.client_id, "Client disconnected");
+
+            session.close.notify_one();
+
+            if let (true, Some(will)) = (publish_will, session.will) {
+                self.route(None, will);
+            }
+        }
+    }
+
+    /// Handle a packet received from the client of a session.
+    fn handle(&mut self, id: u64, packet: Packet) -> io::Result<()> {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return Ok(());
+        };
+
+        match packet {
+            Packet::Publish(Publish {
+                packet_id,
+                topic_alias,
+                mut publication,
+            }) => {
+                if let Some(alias) = topic_alias {
+                    if publication.topic.is_empty() {
+                        publication.topic = session
+                            .topic_aliases
+                            .get(&alias)
+                            .cloned()
+                            .ok_or_else(|| protocol_error("Unknown topic alias"))?;
+                    } else {
+                        session
+                            .topic_aliases
+                            .insert(alias, publication.topic.clone());
+                    }
+                }
+
+                match (publication.qos, packet_id) {
+                    (1, Some(packet_id)) => session.send(codec::ack(codec::PUBACK, packet_id)),
+                    (2, Some(packet_id)) => {
+                        session.send(codec::ack(codec::PUBREC, packet_id));
+
+                        // A redelivered message that has already been routed
+                        if !session.awaiting_release.insert(packet_id) {
+                            return Ok(());
+                        }
+                    }
+                    _ => {}
+                }
+
+                self.route(Some(id), publication);
+            }
+            Packet::PubRec(packet_id) => session.send(codec::ack(codec::PUBREL, packet_id)),
+            Packet::PubRel(packet_id) => {
+                session.awaiting_release.remove(&packet_id);
+                session.send(codec::ack(codec::PUBCOMP, packet_id));
+            }
+            Packet::PubAck | Packet::PubComp => {}
+            Packet::Subscribe {
+                packet_id,
+                subscription_id,
+                filters,
+            } => self.subscribe(id, packet_id, subscription_id, filters),
+            Packet::Unsubscribe { packet_id, filters } => {
+                let codes: Vec<u8> = filters
+                    .iter()
+                    .map(|filter| {
+                        let count = session.subscriptions.len();
+                        session.subscriptions.retain(|s| &s.filter != filter);
+                        if session.subscriptions.len() < count {
+                            0x00
+                        } else {
+                            // No subscription existed
+                            0x11
+                        }
+                    })
+                    .collect();
+
+                session.send(codec::unsuback(session.version, packet_id, &codes));
+            }
+            Packet::PingReq => session.send(codec::pingresp()),
+            Packet::Connect(_) => return Err(protocol_error("Unexpected CONNECT")),
+            Packet::Disconnect { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    fn subscribe(
+        &mut self,
+        id: u64,
+        packet_id: u16,
+        subscription_id: Option<u32>,
+        filters: Vec<(String, u8)>,
+    ) {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return;
+        };
+        let v5 = session.version == 5;
+
+        let mut codes = Vec::new();
+        let mut send_retained = Vec::new();
+
+        for (filter, options) in filters {
+            if !topic::is_valid_filter(&filter) {
+                codes.push(0x80);
+                continue;
+            }
+
+            let subscription = SessionSubscription {
+                filter,
+                qos: (options & 0x03).min(2),
+                no_local: v5 && options & 0x04 != 0,
+                retain_as_published: v5 && options & 0x08 != 0,
+                subscription_id,
+            };
+            let retain_handling = if v5 { (options >> 4) & 0x03 } else { 0 };
+
+            let count = session.subscriptions.len();
+            session
+                .subscriptions
+                .retain(|s| s.filter != subscription.filter);
+            let existed = session.subscriptions.len() < count;
+
+            if !subscription.is_shared()
+                && (retain_handling == 0 || retain_handling == 1 && !existed)
+            {
+                send_retained.push(subscription.clone());
+            }
+
+            codes.push(subscription.qos);
+            session.subscriptions.push(subscription);
+        }
+
+        session.send(codec::suback(session.version, packet_id, &codes));
+
+        for subscription in send_retained {
+            for publication in self.retained.values() {
+                if topic::matches(&subscription.filter, &publication.topic) {
+                    session.publish(
+                        publication,
+                        publication.qos.min(subscription.qos),
+                        true,
+                        &Vec::from_iter(subscription.subscription_id),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Deliver a message to all matching subscriptions, `from` is the session that published it.
+    fn route(&mut self, from: Option<u64>, publication: Publication) {
+        if publication.retain {
+            if publication.payload.is_empty() {
+                self.retained.remove(&publication.topic);
+            } else {
+                self.retained
+                    .insert(publication.topic.clone(), publication.clone());
+            }
+        }
+
+        let mut shared: BTreeMap<String, Vec<(u64, SessionSubscription)>> = BTreeMap::new();
+
+        for (id, session) in &mut self.sessions {
+            let mut qos = None;
+            let mut retain = false;
+            let mut ids = Vec::new();
+
+            for s in &session.subscriptions {
+                if !topic::matches(&s.filter, &publication.topic) {
+                    continue;
+                }
+
+                if s.is_shared() {
+                    shared
+                        .entry(s.filter.clone())
+                        .or_default()
+                        .push((*id, s.clone()));
+                    continue;
+                }
+
+                if s.no_local && from == Some(*id) {
+                    continue;
+                }
+
+                // Overlapping subscriptions result in a single delivery at the highest QoS
+                qos = qos.max(Some(s.qos));
+                retain |= s.retain_as_published && publication.retain;
+                ids.extend(s.subscription_id);
+            }
+
+            if let Some(qos) = qos {
+                session.publish(&publication, qos.min(publication.qos), retain, &ids);
+            }
+        }
+
+        for mut members in shared.into_values() {
+            members.sort_by_key(|(id, _)| *id);
+            let (id, s) = &members[self.next_shared % members.len()];
+            self.next_shared = self.next_shared.wrapping_add(1);
+
+            if let Some(session) = self.sessions.get_mut(id) {
+                session.publish(
+                    &publication,
+                    s.qos.min(publication.qos),
+                    s.retain_as_published && publication.retain,
+                    &Vec::from_iter(s.subscription_id),
+                );
+            }
+        }
+    }
+}
+
+fn protocol_error(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Serve a client connection until it is closed.
+async fn connection(state: Arc<Mutex<State>>, stream: TcpStream) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, writer) = stream.into_split();
+
+    let connect = match codec::read_packet(&mut reader, 0).await {
+        Ok(Packet::Connect(connect)) => connect,
+        _ => return,
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(write_packets(writer, rx));
+
+    let version = connect.version;
+    if version != 4 && version != 5 {
+        // Unacceptable protocol version
+        let _ = tx.send(codec::connack(4, 0x01, None));
+        return;
+    }
+
+    let close = Arc::new(Notify::new());
+    let id = state.lock().unwrap().connect(connect, tx, close.clone());
+
+    let publish_will = loop {
+        let packet = tokio::select! {
+            packet = codec::read_packet(&mut reader, version) => packet,
+            _ = close.notified() => break true,
+        };
+
+        match packet {
+            // Disconnect with will message
+            Ok(Packet::Disconnect { reason }) => break reason == 0x04,
+            Ok(packet) => {
+                if let Err(e) = state.lock().unwrap().handle(id, packet) {
+                    tracing::debug!(error = %e, "Closing connection");
+                    break true;
+                }
+            }
+            Err(_) => break true,
+        }
+    };
+
+    state.lock().unwrap().disconnect(id, publish_will);
+}
+
+/// Write packets to a client until the session is removed.
+async fn write_packets(mut writer: OwnedWriteHalf, mut rx: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(packet) = rx.recv().await {
+        if writer.write_all(&packet).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(all(test, any(feature = "paho", feature = "rumqttc")))]
+mod tests {
+    use super::*;
+    use crate::{
+        Client, ClientConfig, ConnectOptionsBuilder, Event, Message, SubscriptionBuilder,
+        Transport, MQTT_VERSION_3_1_1, MQTT_VERSION_5,
+    };
+    use std::time::Duration;
+    use tokio::sync::broadcast::Receiver;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Backend {
+        #[cfg(feature = "paho")]
+        Paho,
+        #[cfg(feature = "rumqttc")]
+        Rumqttc,
+    }
+
+    const BACKENDS: &[Backend] = &[
+        #[cfg(feature = "paho")]
+        Backend::Paho,
+        #[cfg(feature = "rumqttc")]
+        Backend::Rumqttc,
+    ];
+
+    /// Client connected to the broker, with the transport it uses to wait for acknowledgements.
+    struct TestClient {
+        client: Client,
+        transport: Box<dyn Transport>,
+        rx: Receiver<Event>,
+    }
+
+    impl TestClient {
+        async fn start(
+            broker: &LoopbackBroker,
+            backend: Backend,
+            client_id: &str,
+            mqtt_version: u32,
+            will: Option<Message>,
+        ) -> Self {
+            let (client, transport): (Client, Box<dyn Transport>) = match backend {
+                #[cfg(feature = "paho")]
+                Backend::Paho => {
+                    let options = paho_mqtt::CreateOptionsBuilder::new()
+                        .server_uri(broker.uri())
+                        .client_id(client_id)
+                        .mqtt_version(mqtt_version)
+                        .finalize();
+                    let transport = paho_mqtt::AsyncClient::new(options).unwrap();
+                    (
+                        Client::with_transport(transport.clone(), ClientConfig::default()),
+                        Box::new(transport),
+                    )
+                }
+                #[cfg(feature = "rumqttc")]
+                Backend::Rumqttc => {
+                    let transport =
+                        crate::RumqttcTransport::with_uri(&broker.uri(), client_id, mqtt_version)
+                            .unwrap();
+                    (
+                        Client::with_transport(transport.clone(), ClientConfig::default()),
+                        Box::new(transport),
+                    )
+                }
+            };
+            let rx = client.rx_channel();
+
+            let mut options = ConnectOptionsBuilder::default();
+            options.mqtt_version(mqtt_version);
+            if let Some(will) = will {
+                options.will_message(will);
+            }
+            client.start(options.build().unwrap()).await.unwrap();
+
+            Self {
+                client,
+                transport,
+                rx,
+            }
+        }
+
+        /// Subscribe, waiting for the subscription to be acknowledged.
+        async fn subscribe(&self, topic: &str, qos: i32) {
+            let subscription = SubscriptionBuilder::default()
+                .topic(topic.to_owned())
+                .qos(qos)
+                .build()
+                .unwrap();
+            self.transport.subscribe(&subscription).await.unwrap();
+        }
+
+        /// Publish, waiting for the message to be acknowledged.
+        async fn publish(&self, msg: Message) {
+            self.transport.publish(msg).unwrap().await.unwrap();
+        }
+
+        /// Receive the next message, or `None` if there is none within a short time.
+        async fn recv(&mut self) -> Option<Message> {
+            loop {
+                match tokio::time::timeout(Duration::from_secs(2), self.rx.recv()).await {
+                    Ok(Ok(Event::Rx(msg))) => return Some(msg),
+                    Ok(Ok(_)) => {}
+                    _ => return None,
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retained() {
+        for &backend in BACKENDS {
+            let broker = LoopbackBroker::start().await.unwrap();
+
+            let publisher =
+                TestClient::start(&broker, backend, "publisher", MQTT_VERSION_3_1_1, None).await;
+            publisher
+                .publish(Message::new_retained("status/a", "online", 1))
+                .await;
+            assert_eq!(broker.retained_topics(), ["status/a"], "{:?}", backend);
+
+            let mut subscriber =
+                TestClient::start(&broker, backend, "subscriber", MQTT_VERSION_3_1_1, None).await;
+            subscriber.subscribe("status/+", 1).await;
+            let msg = subscriber.recv().await.unwrap();
+            assert_eq!(msg.topic(), "status/a");
+            assert_eq!(msg.payload(), b"online");
+            assert!(msg.retained());
+
+            // An empty retained message clears it
+            publisher
+                .publish(Message::new_retained("status/a", Vec::new(), 1))
+                .await;
+            assert!(broker.retained_topics().is_empty(), "{:?}", backend);
+
+            publisher.client.stop().await.unwrap();
+            subscriber.client.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn wildcards() {
+        for &backend in BACKENDS {
+            let broker = LoopbackBroker::start().await.unwrap();
+
+            let mut subscriber =
+                TestClient::start(&broker, backend, "subscriber", MQTT_VERSION_5, None).await;
+            subscriber.subscribe("a/+/c", 0).await;
+            subscriber.subscribe("b/#", 0).await;
+            let publisher =
+                TestClient::start(&broker, backend, "publisher", MQTT_VERSION_5, None).await;
+
+            // Messages from one publisher are delivered in order, the last one marks the end
+            for topic in ["a/b/c", "a/b/d", "a/b/c/d", "b", "c", "b/c/d", "b/end"] {
+                publisher.publish(Message::new(topic, topic, 1)).await;
+            }
+
+            let mut topics = Vec::new();
+            while let Some(msg) = subscriber.recv().await {
+                if msg.topic() == "b/end" {
+                    break;
+                }
+                topics.push(msg.topic().to_owned());
+            }
+            assert_eq!(topics, ["a/b/c", "b", "b/c/d"], "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn qos() {
+        for &backend in BACKENDS {
+            for mqtt_version in [MQTT_VERSION_3_1_1, MQTT_VERSION_5] {
+                let broker = LoopbackBroker::start().await.unwrap();
+
+                let mut subscriber =
+                    TestClient::start(&broker, backend, "subscriber", mqtt_version, None).await;
+                subscriber.subscribe("one", 1).await;
+                subscriber.subscribe("two", 2).await;
+                let publisher =
+                    TestClient::start(&broker, backend, "publisher", mqtt_version, None).await;
+
+                // The QoS of a delivered message is the lower of the published and subscribed QoS
+                for (topic, qos, delivered) in [("one", 2, 1), ("two", 1, 1), ("two", 2, 2)] {
+                    publisher.publish(Message::new(topic, "payload", qos)).await;
+
+                    let msg = subscriber.recv().await.unwrap();
+                    assert_eq!(msg.topic(), topic, "{:?}", backend);
+                    assert_eq!(msg.qos(), delivered, "{:?}", backend);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn will() {
+        for &backend in BACKENDS {
+            let broker = LoopbackBroker::start().await.unwrap();
+
+            let mut subscriber =
+                TestClient::start(&broker, backend, "subscriber", MQTT_VERSION_5, None).await;
+            subscriber.subscribe("status/+", 1).await;
+
+            let will = Message::new("status/device", "offline", 1);
+            let _device =
+                TestClient::start(&broker, backend, "device", MQTT_VERSION_5, Some(will)).await;
+
+            assert!(broker.drop_client("device"));
+            let msg = subscriber.recv().await.unwrap();
+            assert_eq!(msg.topic(), "status/device", "{:?}", backend);
+            assert_eq!(msg.payload(), b"offline");
+            assert!(!broker.client_ids().contains(&"device".to_owned()));
+        }
+    }
+}
@@ -0,0 +1,594 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub(super) const PUBACK: u8 = 0x40;
+pub(super) const PUBREC: u8 = 0x50;
+pub(super) const PUBREL: u8 = 0x62;
+pub(super) const PUBCOMP: u8 = 0x70;
+
+const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+const WILL_DELAY_INTERVAL: u8 = 0x18;
+const TOPIC_ALIAS: u8 = 0x23;
+
+pub(super) enum Packet {
+    Connect(Connect),
+    Publish(Publish),
+    PubAck,
+    PubRec(u16),
+    PubRel(u16),
+    PubComp,
+    Subscribe {
+        packet_id: u16,
+        subscription_id: Option<u32>,
+        filters: Vec<(String, u8)>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    PingReq,
+    Disconnect {
+        reason: u8,
+    },
+}
+
+pub(super) struct Connect {
+    pub(super) version: u8,
+    pub(super) client_id: String,
+    pub(super) will: Option<Publication>,
+}
+
+pub(super) struct Publish {
+    pub(super) packet_id: Option<u16>,
+    pub(super) topic_alias: Option<u16>,
+    pub(super) publication: Publication,
+}
+
+/// A message as it is routed between clients.
+#[derive(Debug, Clone)]
+pub(super) struct Publication {
+    pub(super) topic: String,
+    pub(super) payload: Vec<u8>,
+    pub(super) qos: u8,
+    pub(super) retain: bool,
+
+    /// MQTT v5 properties that are forwarded to subscribers.
+    pub(super) properties: Vec<Property>,
+}
+
+/// An MQTT v5 property, with its value as it is encoded.
+#[derive(Debug, Clone)]
+pub(super) struct Property {
+    id: u8,
+    value: Vec<u8>,
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed packet")
+}
+
+/// Read a packet from a client using the given protocol version, or `0` before it is known.
+pub(super) async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    version: u8,
+) -> io::Result<Packet> {
+    let header = reader.read_u8().await?;
+
+    let mut len = 0;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    let mut d = Decoder { buf: &body };
+
+    let v5 = version == 5;
+
+    Ok(match header >> 4 {
+        1 => Packet::Connect(d.connect()?),
+        _ if version == 0 => return Err(malformed()),
+        3 => Packet::Publish(d.publish(header, v5)?),
+        4 => Packet::PubAck,
+        5 => Packet::PubRec(d.u16()?),
+        6 => Packet::PubRel(d.u16()?),
+        7 => Packet::PubComp,
+        8 => {
+            let packet_id = d.u16()?;
+            let subscription_id = if v5 {
+                d.properties()?
+                    .iter()
+                    .find(|p| p.id == SUBSCRIPTION_IDENTIFIER)
+                    .map(|p| Decoder { buf: &p.value }.varint())
+                    .transpose()?
+            } else {
+                None
+            };
+
+            let mut filters = Vec::new();
+            while !d.buf.is_empty() {
+                filters.push((d.string()?, d.u8()?));
+            }
+
+            Packet::Subscribe {
+                packet_id,
+                subscription_id,
+                filters,
+            }
+        }
+        10 => {
+            let packet_id = d.u16()?;
+            if v5 {
+                d.properties()?;
+            }
+
+            let mut filters = Vec::new();
+            while !d.buf.is_empty() {
+                filters.push(d.string()?);
+            }
+
+            Packet::Unsubscribe { packet_id, filters }
+        }
+        12 => Packet::PingReq,
+        14 => Packet::Disconnect {
+            reason: if d.buf.is_empty() { 0 } else { d.u8()? },
+        },
+        _ => return Err(malformed()),
+    })
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(malformed());
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn varint(&mut self) -> io::Result<u32> {
+        let mut value = 0;
+        for shift in (0..28).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed())
+    }
+
+    fn binary(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.binary()?).map_err(|_| malformed())
+    }
+
+    /// Length of the encoded value of a property.
+    fn property_len(&self, id: u8) -> io::Result<usize> {
+        let prefixed = |buf: &[u8]| match buf {
+            [a, b, ..] => Ok(2 + u16::from_be_bytes([*a, *b]) as usize),
+            _ => Err(malformed()),
+        };
+
+        match id {
+            0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => Ok(1),
+            0x13 | 0x21 | 0x22 | 0x23 => Ok(2),
+            0x02 | 0x11 | 0x18 | 0x27 => Ok(4),
+            0x0B => Ok(1 + self.buf.iter().take_while(|b| *b & 0x80 != 0).count()),
+            0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1A | 0x1C | 0x1F => prefixed(self.buf),
+            0x26 => {
+                let key = prefixed(self.buf)?;
+                Ok(key + prefixed(self.buf.get(key..).ok_or_else(malformed)?)?)
+            }
+            _ => Err(malformed()),
+        }
+    }
+
+    fn properties(&mut self) -> io::Result<Vec<Property>> {
+        let len = self.varint()? as usize;
+        let mut d = Decoder {
+            buf: self.take(len)?,
+        };
+
+        let mut properties = Vec::new();
+        while !d.buf.is_empty() {
+            let id = d.u8()?;
+            let value = d.take(d.property_len(id)?)?.to_vec();
+            properties.push(Property { id, value });
+        }
+        Ok(properties)
+    }
+
+    fn connect(&mut self) -> io::Result<Connect> {
+        let _protocol_name = self.string()?;
+        let version = self.u8()?;
+        let flags = self.u8()?;
+        let _keep_alive = self.u16()?;
+
+        let v5 = version == 5;
+        if v5 {
+            self.properties()?;
+        }
+
+        let client_id = self.string()?;
+
+        let will = if flags & 0x04 != 0 {
+            let properties = if v5 {
+                self.properties()?
+                    .into_iter()
+                    .filter(|p| p.id != WILL_DELAY_INTERVAL)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            Some(Publication {
+                topic: self.string()?,
+                payload: self.binary()?,
+                qos: (flags >> 3) & 0x03,
+                retain: flags & 0x20 != 0,
+                properties,
+            })
+        } else {
+            None
+        };
+
+        // Credentials are accepted without being checked
+
+        Ok(Connect {
+            version,
+            client_id,
+            will,
+        })
+    }
+
+    fn publish(&mut self, header: u8, v5: bool) -> io::Result<Publish> {
+        let qos = (header >> 1) & 0x03;
+        let retain = header & 0x01 != 0;
+
+        let topic = self.string()?;
+        let packet_id = match qos {
+            0 => None,
+            _ => Some(self.u16()?),
+        };
+
+        let mut topic_alias = None;
+        let mut properties = Vec::new();
+        if v5 {
+            for p in self.properties()? {
+                match p.id {
+                    TOPIC_ALIAS => topic_alias = Some(Decoder { buf: &p.value }.u16()?),
+                    SUBSCRIPTION_IDENTIFIER => {}
+                    _ => properties.push(p),
+                }
+            }
+        }
+
+        Ok(Publish {
+            packet_id,
+            topic_alias,
+            publication: Publication {
+                topic,
+                payload: self.buf.to_vec(),
+                qos,
+                retain,
+                properties,
+            },
+        })
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.buf.extend(value.to_be_bytes());
+        self
+    }
+
+    fn varint(&mut self, mut value: usize) -> &mut Self {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.buf.push(byte);
+                return self;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    fn properties<'a>(&mut self, properties: impl IntoIterator<Item = &'a Property>) -> &mut Self {
+        let mut encoded = Encoder::default();
+        for p in properties {
+            encoded.u8(p.id).bytes(&p.value);
+        }
+        self.varint(encoded.buf.len()).bytes(&encoded.buf)
+    }
+
+    /// Prefix the encoded variable header and payload with the fixed header of a packet.
+    fn packet(&self, header: u8) -> Vec<u8> {
+        let mut packet = Encoder::default();
+        packet.u8(header).varint(self.buf.len()).bytes(&self.buf);
+        packet.buf
+    }
+}
+
+pub(super) fn connack(version: u8, reason: u8, assigned_client_id: Option<&str>) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.u8(0).u8(reason);
+
+    if version == 5 {
+        let assigned = assigned_client_id.map(|id| Property {
+            id: ASSIGNED_CLIENT_IDENTIFIER,
+            value: Encoder::default().string(id).buf.clone(),
+        });
+        e.properties(&assigned);
+    }
+
+    e.packet(0x20)
+}
+
+pub(super) fn publish(
+    version: u8,
+    packet_id: Option<u16>,
+    publication: &Publication,
+    qos: u8,
+    retain: bool,
+    subscription_ids: &[u32],
+) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.string(&publication.topic);
+
+    if let Some(packet_id) = packet_id {
+        e.u16(packet_id);
+    }
+
+    if version == 5 {
+        let ids: Vec<Property> = subscription_ids
+            .iter()
+            .map(|id| Property {
+                id: SUBSCRIPTION_IDENTIFIER,
+                value: Encoder::default().varint(*id as usize).buf.clone(),
+            })
+            .collect();
+        e.properties(publication.properties.iter().chain(&ids));
+    }
+
+    e.bytes(&publication.payload);
+    e.packet(0x30 | (qos << 1) | retain as u8)
+}
+
+/// Encode a packet acknowledging a publish, with the given fixed header.
+pub(super) fn ack(header: u8, packet_id: u16) -> Vec<u8> {
+    Encoder::default().u16(packet_id).packet(header)
+}
+
+pub(super) fn suback(version: u8, packet_id: u16, codes: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.u16(packet_id);
+    if version == 5 {
+        e.properties(None);
+    }
+    e.bytes(codes).packet(0x90)
+}
+
+pub(super) fn unsuback(version: u8, packet_id: u16, codes: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.u16(packet_id);
+    if version == 5 {
+        e.properties(None).bytes(codes);
+    }
+    e.packet(0xB0)
+}
+
+pub(super) fn pingresp() -> Vec<u8> {
+    Encoder::default().packet(0xD0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn property(id: u8, value: &[u8]) -> Property {
+        Property {
+            id,
+            value: value.to_vec(),
+        }
+    }
+
+    fn publication(properties: Vec<Property>) -> Publication {
+        Publication {
+            topic: "a/b".into(),
+            payload: b"payload".to_vec(),
+            qos: 1,
+            retain: true,
+            properties,
+        }
+    }
+
+    /// Encode a publish as it is sent to a client and read it back as it is received from one.
+    async fn round_trip(version: u8, publication: &Publication) -> Publish {
+        let packet = publish(version, Some(7), publication, 1, true, &[3, 200]);
+        match read_packet(&mut packet.as_slice(), version).await.unwrap() {
+            Packet::Publish(publish) => publish,
+            _ => panic!("not a publish packet"),
+        }
+    }
+
+    #[test]
+    fn varint() {
+        for (value, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16_383, 2),
+            (16_384, 3),
+            (2_097_151, 3),
+            (2_097_152, 4),
+            (268_435_455, 4),
+        ] {
+            let mut e = Encoder::default();
+            e.varint(value);
+            assert_eq!(e.buf.len(), len, "length of {}", value);
+            assert_eq!(Decoder { buf: &e.buf }.varint().unwrap(), value as u32);
+        }
+
+        assert!(Decoder {
+            buf: &[0xff, 0xff, 0xff, 0xff, 0x01]
+        }
+        .varint()
+        .is_err());
+        assert!(Decoder { buf: &[0x80] }.varint().is_err());
+    }
+
+    #[tokio::test]
+    async fn remaining_length() {
+        for len in [0, 100, 200, 20_000] {
+            let mut publication = publication(Vec::new());
+            publication.payload = vec![0x55; len];
+
+            let publish = round_trip(4, &publication).await;
+            assert_eq!(publish.publication.payload, publication.payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn properties() {
+        let properties = vec![
+            property(0x01, &[1]),
+            property(0x02, &60u32.to_be_bytes()),
+            property(0x03, b"\x00\x0atext/plain"),
+            property(0x08, b"\x00\x08response"),
+            property(0x09, b"\x00\x03\x01\x02\x03"),
+            property(0x26, b"\x00\x03key\x00\x05value"),
+            property(0x26, b"\x00\x03key\x00\x06second"),
+        ];
+
+        let publish = round_trip(5, &publication(properties.clone())).await;
+        assert_eq!(publish.packet_id, Some(7));
+        assert_eq!(publish.topic_alias, None);
+        assert_eq!(publish.publication.topic, "a/b");
+        assert_eq!(publish.publication.payload, b"payload");
+        assert_eq!(publish.publication.qos, 1);
+        assert!(publish.publication.retain);
+
+        // Subscription identifiers are added for the subscriber and not forwarded
+        let decoded: Vec<_> = publish
+            .publication
+            .properties
+            .iter()
+            .map(|p| (p.id, p.value.clone()))
+            .collect();
+        let expected: Vec<_> = properties.iter().map(|p| (p.id, p.value.clone())).collect();
+        assert_eq!(decoded, expected);
+
+        // Properties are only encoded for MQTT v5
+        let publish = round_trip(4, &publication(properties)).await;
+        assert!(publish.publication.properties.is_empty());
+    }
+
+    #[tokio::test]
+    async fn topic_alias() {
+        let publish = round_trip(5, &publication(vec![property(TOPIC_ALIAS, &[0, 2])])).await;
+        assert_eq!(publish.topic_alias, Some(2));
+        assert!(publish.publication.properties.is_empty());
+    }
+
+    #[test]
+    fn malformed_properties() {
+        // Truncated user property
+        let mut d = Decoder {
+            buf: b"\x06\x26\x00\x03key",
+        };
+        assert!(d.properties().is_err());
+
+        // Unknown property
+        let mut d = Decoder {
+            buf: b"\x02\x7f\x00",
+        };
+        assert!(d.properties().is_err());
+    }
+
+    #[test]
+    fn suback() {
+        assert_eq!(
+            super::suback(4, 0x0102, &[0x00, 0x01]),
+            [0x90, 4, 0x01, 0x02, 0x00, 0x01]
+        );
+        assert_eq!(
+            super::suback(5, 0x0102, &[0x00, 0x02]),
+            [0x90, 5, 0x01, 0x02, 0x00, 0x00, 0x02]
+        );
+    }
+
+    #[test]
+    fn unsuback() {
+        assert_eq!(super::unsuback(4, 0x0102, &[0x00]), [0xB0, 2, 0x01, 0x02]);
+        assert_eq!(
+            super::unsuback(5, 0x0102, &[0x00, 0x11]),
+            [0xB0, 5, 0x01, 0x02, 0x00, 0x00, 0x11]
+        );
+    }
+
+    #[test]
+    fn connack() {
+        assert_eq!(super::connack(4, 0, None), [0x20, 2, 0, 0]);
+        assert_eq!(super::connack(5, 0, None), [0x20, 3, 0, 0, 0]);
+        assert_eq!(
+            super::connack(5, 0, Some("id")),
+            [
+                0x20,
+                8,
+                0,
+                0,
+                5,
+                ASSIGNED_CLIENT_IDENTIFIER,
+                0,
+                2,
+                b'i',
+                b'd'
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{topic, Message};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+
+/// Cache of the latest message received on each topic.
+///
+/// Messages with an empty payload, which clear the retained message of a topic, remove the topic
+/// from the cache. Cached messages keep their retained flag, which is set when the message was
+/// delivered by the broker because it was retained at the time of subscribing.
+///
+/// Responses to requests are not cached.
+#[derive(Clone)]
+pub struct LastValueCache {
+    topics: Arc<Mutex<HashMap<String, watch::Sender<Option<Message>>>>>,
+    changes: broadcast::Sender<CacheChange>,
+}
+
+/// Change of the cached message of a topic.
+#[derive(Debug, Clone)]
+pub struct CacheChange {
+    /// Topic of the message.
+    pub topic: String,
+
+    /// New message, or `None` if the topic was removed from the cache.
+    pub message: Option<Message>,
+}
+
+/// Receiver of the changes of the topics that match a filter.
+pub struct CacheWatcher {
+    filter: String,
+    rx: broadcast::Receiver<CacheChange>,
+}
+
+impl LastValueCache {
+    pub(crate) fn new(channel_size: usize) -> Self {
+        let (changes, _) = broadcast::channel(channel_size);
+
+        Self {
+            topics: Default::default(),
+            changes,
+        }
+    }
+
+    /// Get the latest message of a topic.
+    pub fn get(&self, topic: &str) -> Option<Message> {
+        let topics = self.topics.lock().unwrap();
+        topics.get(topic).and_then(|tx| tx.borrow().clone())
+    }
+
+    /// Get the latest message of each topic that matches a filter, ordered by topic.
+    pub fn matching(&self, filter: &str) -> Vec<Message> {
+        let topics = self.topics.lock().unwrap();
+
+        let mut messages: Vec<Message> = topics
+            .iter()
+            .filter(|(topic, _)| topic::matches(filter, topic))
+            .filter_map(|(_, tx)| tx.borrow().clone())
+            .collect();
+        messages.sort_by(|a, b| a.topic().cmp(b.topic()));
+        messages
+    }
+
+    /// Watch the latest message of a topic.
+    ///
+    /// The receiver holds `None` while the topic is not cached.
+    pub fn watch(&self, topic: &str) -> watch::Receiver<Option<Message>> {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .entry(topic.to_owned())
+            .or_insert_with(|| watch::channel(None).0)
+            .subscribe()
+    }
+
+    /// Watch the changes of the topics that match a filter.
+    pub fn watch_filter(&self, filter: &str) -> CacheWatcher {
+        CacheWatcher {
+            filter: filter.to_owned(),
+            rx: self.changes.subscribe(),
+        }
+    }
+
+    /// Update the cache with a received message.
+    pub(crate) fn update(&self, msg: &Message) {
+        let mut topics = self.topics.lock().unwrap();
+
+        let message = if msg.payload().is_empty() {
+            // Keep the topic while it is watched, so that watchers see later messages
+            match topics.get(msg.topic()) {
+                Some(tx) if tx.receiver_count() == 0 => {
+                    topics.remove(msg.topic());
+                }
+                Some(tx) => {
+                    tx.send_replace(None);
+                }
+                None => return,
+            }
+            None
+        } else {
+            let message = Some(msg.clone());
+            match topics.get(msg.topic()) {
+                Some(tx) => {
+                    tx.send_replace(message.clone());
+                }
+                None => {
+                    topics.insert(msg.topic().to_owned(), watch::channel(message.clone()).0);
+                }
+            }
+            message
+        };
+
+        // There may be no watchers
+        let _ = self.changes.send(CacheChange {
+            topic: msg.topic().to_owned(),
+            message,
+        });
+    }
+}
+
+impl CacheWatcher {
+    /// Wait for the next change of a topic that matches the filter.
+    ///
+    /// Returns [`RecvError::Lagged`] if changes were missed, after which the current state can be
+    /// read with [`LastValueCache::matching`].
+    pub async fn changed(&mut self) -> Result<CacheChange, RecvError> {
+        loop {
+            let change = self.rx.recv().await?;
+            if topic::matches(&self.filter, &change.topic) {
+                return Ok(change);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update() {
+        let cache = LastValueCache::new(16);
+        assert!(cache.get("a").is_none());
+
+        cache.update(&Message::new("a", "1", 0));
+        cache.update(&Message::new("a", "2", 0));
+        assert_eq!(cache.get("a").unwrap().payload(), b"2");
+
+        // An empty payload removes the topic
+        cache.update(&Message::new("a", "", 0));
+        assert!(cache.get("a").is_none());
+        assert!(cache.matching("#").is_empty());
+    }
+
+    #[test]
+    fn matching() {
+        let cache = LastValueCache::new(16);
+        for topic in ["b/2", "a/1", "b/1", "c"] {
+            cache.update(&Message::new(topic, topic, 0));
+        }
+
+        let topics: Vec<String> = cache
+            .matching("b/+")
+            .iter()
+            .map(|m| m.topic().to_owned())
+            .collect();
+        assert_eq!(topics, ["b/1", "b/2"]);
+        assert_eq!(cache.matching("#").len(), 4);
+    }
+
+    #[test]
+    fn retained() {
+        let cache = LastValueCache::new(16);
+        cache.update(&Message::new_retained("a", "1", 0));
+        assert!(cache.get("a").unwrap().retained());
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let cache = LastValueCache::new(16);
+        let mut rx = cache.watch("a");
+        assert!(rx.borrow().is_none());
+
+        cache.update(&Message::new("a", "1", 0));
+        rx.changed().await.unwrap();
+        assert_eq!(rx.borrow().as_ref().unwrap().payload(), b"1");
+
+        // The topic is kept while it is watched
+        cache.update(&Message::new("a", "", 0));
+        rx.changed().await.unwrap();
+        assert!(rx.borrow().is_none());
+
+        cache.update(&Message::new("a", "2", 0));
+        rx.changed().await.unwrap();
+        assert_eq!(rx.borrow().as_ref().unwrap().payload(), b"2");
+    }
+
+    #[tokio::test]
+    async fn watch_filter() {
+        let cache = LastValueCache::new(16);
+        let mut watcher = cache.watch_filter("a/+");
+
+        cache.update(&Message::new("b/1", "1", 0));
+        cache.update(&Message::new("a/1", "1", 0));
+        cache.update(&Message::new("a/1", "", 0));
+
+        let change = watcher.changed().await.unwrap();
+        assert_eq!(change.topic, "a/1");
+        assert_eq!(change.message.unwrap().payload(), b"1");
+
+        let change = watcher.changed().await.unwrap();
+        assert_eq!(change.topic, "a/1");
+        assert!(change.message.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagged() {
+        let cache = LastValueCache::new(2);
+        let mut watcher = cache.watch_filter("#");
+
+        for i in 0..4 {
+            cache.update(&Message::new(format!("{}", i), "1", 0));
+        }
+
+        assert!(matches!(watcher.changed().await, Err(RecvError::Lagged(2))));
+        assert_eq!(watcher.changed().await.unwrap().topic, "2");
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn client() {
+        use crate::{transport::start_client, ClientConfigBuilder};
+        use std::time::Duration;
+
+        let config = ClientConfigBuilder::default()
+            .last_value_cache(true)
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+
+        transport.inject_message(Message::new_retained("a", "1", 1));
+        let cache = client.last_value_cache().unwrap();
+        assert_eq!(cache.get("a").unwrap().payload(), b"1");
+
+        // Responses to requests are not cached
+        let request = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .request("service", "request", Duration::from_secs(5))
+                    .await
+            })
+        };
+        let published = transport.wait_for_published(1).await;
+        let properties = published[0].properties();
+        transport.inject_message(
+            crate::MessageBuilder::new(properties.response_topic.clone().unwrap(), "response")
+                .correlation_data(properties.correlation_data.clone().unwrap())
+                .build(),
+        );
+
+        assert_eq!(request.await.unwrap().unwrap().payload(), b"response");
+        assert_eq!(cache.matching("#").len(), 1);
+    }
+}
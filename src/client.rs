@@ -1,52 +1,142 @@
-#[cfg(feature = "metrics")]
-use crate::metrics::{ConnectionEventLabels, MessageLabelsBuilder, MetricCollection};
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+use crate::metrics::{ConnectionEventLabels, MetricCollection};
 use crate::{
+    dedup::{Deduplication, DeduplicationKey, Deduplicator},
     events::{Event, StatusEvent},
-    ClientConfig, Subscription,
+    rate_limit::{Admission, RateLimiter},
+    rpc::{PendingRequests, RpcMode},
+    topic, ClientConfig, ConnectOptions, CredentialProvider, Credentials, LastValueCache, Message,
+    MessageBuilder, Subscription, SubscriptionBuilder, Transport, TransportEvent,
 };
-use paho_mqtt::{AsyncClient, ConnectOptions, CreateOptions, Message};
+#[cfg(feature = "paho")]
+use paho_mqtt::{AsyncClient, CreateOptions};
 #[cfg(feature = "metrics")]
 use prometheus_client::registry::Registry;
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
-    sync::broadcast::{self, Receiver, Sender},
+    sync::{
+        broadcast::{self, Receiver, Sender},
+        mpsc, oneshot, Notify, OnceCell,
+    },
     task::JoinHandle,
 };
+use tracing::Instrument;
 
 /// Channel based MQTT client.
 #[derive(Clone)]
 pub struct Client {
-    client: AsyncClient,
-    #[allow(dead_code)]
+    transport: Arc<dyn Transport>,
     config: ClientConfig,
     subscriptions: Arc<Mutex<Vec<Subscription>>>,
 
+    response_topic: String,
+    response_subscription: Arc<OnceCell<()>>,
+    pending_requests: PendingRequests,
+
+    last_value_cache: Option<LastValueCache>,
+
+    retained_discovery: RetainedDiscovery,
+
     tx_channel: Sender<Event>,
     handle: Arc<tokio::sync::Mutex<Option<JoinHandle<()>>>>,
+    reconnect_task: Arc<tokio::sync::Mutex<Option<JoinHandle<()>>>>,
 
-    #[cfg(feature = "metrics")]
+    #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
     metrics: MetricCollection,
+    #[cfg(feature = "metrics-server")]
+    metrics_server: Arc<tokio::sync::Mutex<Option<JoinHandle<()>>>>,
+
+    span: tracing::Span,
 }
 
 impl Client {
-    /// Create a new client using the supplied options.
+    /// Create a new client using the paho backend with the supplied options.
+    #[cfg(feature = "paho")]
     pub fn new(options: CreateOptions, config: ClientConfig) -> Result<Self, crate::Error> {
+        Ok(Self::with_transport(AsyncClient::new(options)?, config))
+    }
+
+    /// Create a new client that communicates with the broker using the supplied transport.
+    pub fn with_transport(transport: impl Transport + 'static, config: ClientConfig) -> Self {
         let (tx, _) = broadcast::channel::<Event>(config.channel_size);
 
-        Ok(Self {
-            client: AsyncClient::new(options)?,
+        let client_id = transport.client_id();
+        let response_topic = format!("{}/{}", config.response_topic_prefix, client_id);
+        let pending_requests = PendingRequests::new(&client_id);
+
+        let span = tracing::info_span!(
+            "mqtt_client",
+            client_id = %client_id,
+            broker = %transport.server_uri(),
+        );
+
+        if let Some(Deduplication {
+            key: DeduplicationKey::PayloadHash,
+            ..
+        }) = &config.deduplication
+        {
+            if !transport.reports_redeliveries() {
+                tracing::warn!(
+                    parent: &span,
+                    "Deduplication by payload hash has no effect, the transport does not report redeliveries"
+                );
+            }
+        }
+
+        let subscriptions = Arc::new(Mutex::new(config.subscriptions.clone()));
+
+        let last_value_cache = config
+            .last_value_cache
+            .then(|| LastValueCache::new(config.channel_size));
+
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+        let metrics = MetricCollection::new(&config, subscriptions.clone());
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+        metrics.set_active_subscriptions(config.subscriptions.len());
+
+        Self {
+            transport: Arc::new(transport),
             config,
-            subscriptions: Default::default(),
+            subscriptions,
+
+            response_topic,
+            response_subscription: Default::default(),
+            pending_requests,
+
+            last_value_cache,
+
+            retained_discovery: Default::default(),
 
             tx_channel: tx,
             handle: Default::default(),
+            reconnect_task: Default::default(),
 
-            #[cfg(feature = "metrics")]
-            metrics: Default::default(),
-        })
+            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+            metrics,
+            #[cfg(feature = "metrics-server")]
+            metrics_server: Default::default(),
+
+            span,
+        }
+    }
+
+    pub(crate) fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Span that events of this client are recorded in.
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
     }
 
     /// Get a sending channel for sending events to the client.
+    ///
+    /// Messages sent directly on the channel do not have the trace context added and are not
+    /// counted in the queue depth metric, prefer [`Client::send`] for sending messages.
     pub fn tx_channel(&self) -> Sender<Event> {
         self.tx_channel.clone()
     }
@@ -56,9 +146,33 @@ impl Client {
         self.tx_channel.subscribe()
     }
 
+    /// Get the cache of the latest message received on each topic, if enabled in the
+    /// configuration.
+    pub fn last_value_cache(&self) -> Option<&LastValueCache> {
+        self.last_value_cache.as_ref()
+    }
+
     /// Send a message.
+    ///
+    /// With the `tracing` feature enabled and an MQTT v5 client, the trace context of the current
+    /// span is added to the message.
     pub fn send(&self, msg: Message) -> crate::Result<()> {
-        self.tx_channel.send(Event::Tx(msg))?;
+        #[cfg(feature = "tracing")]
+        let msg = if self.transport.mqtt_version() >= crate::MQTT_VERSION_5 {
+            crate::trace::inject_trace_context(msg)
+        } else {
+            msg
+        };
+
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+        self.metrics.message_queued();
+
+        if let Err(e) = self.tx_channel.send(Event::Tx(msg)) {
+            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+            self.metrics.messages_dequeued(1);
+
+            return Err(e.into());
+        }
         Ok(())
     }
 
@@ -70,19 +184,304 @@ impl Client {
     /// In all cases the subscription is added to the cache to be subscribed on reconnect.
     pub fn subscribe(&self, subscription: Subscription) {
         // Add to the cached list of subscriptions
-        self.subscriptions
+        self.cache_subscription(subscription.clone());
+
+        // Subscribe now if the client is connected
+        if self.transport.is_connected() {
+            tracing::debug!(
+                parent: &self.span,
+                topic = %subscription.broker_topic(),
+                qos = subscription.qos,
+                "Adding subscription to active client"
+            );
+            drop(self.transport.subscribe(&subscription));
+        }
+    }
+
+    /// Remove a subscription, given the topic filter as it is subscribed to on the broker (see
+    /// [`Subscription::broker_topic`]).
+    ///
+    /// The subscription is removed from the cache so that it is not subscribed on reconnect. If the
+    /// client is currently connected and the cache has no other subscription to the same filter,
+    /// the filter is unsubscribed from for the connected session.
+    pub fn unsubscribe(&self, broker_topic: &str) {
+        let remaining = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            if let Some(i) = subscriptions
+                .iter()
+                .position(|s| s.broker_topic() == broker_topic)
+            {
+                subscriptions.remove(i);
+            }
+
+            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+            self.metrics.set_active_subscriptions(subscriptions.len());
+
+            subscriptions
+                .iter()
+                .any(|s| s.broker_topic() == broker_topic)
+        };
+
+        if !remaining && self.transport.is_connected() {
+            tracing::debug!(
+                parent: &self.span,
+                topic = %broker_topic,
+                "Removing subscription from active client"
+            );
+            drop(self.transport.unsubscribe(broker_topic));
+        }
+    }
+
+    /// Send a request and wait for the matching response.
+    ///
+    /// How the request and response are associated depends on the [`RpcMode`] of the client.
+    ///
+    /// When using [`RpcMode::Properties`] the request is published to `topic` with the MQTT v5
+    /// response topic and correlation data properties set, so the broker must be connected to
+    /// using MQTT v5. The client's response topic is subscribed to when the first request is made.
+    ///
+    /// When using [`RpcMode::TopicConvention`] `topic` is the name of the service, the response
+    /// topic for the request is subscribed to for the duration of the request.
+    ///
+    /// The timeout includes waiting for the response topic to be subscribed to.
+    pub async fn request(
+        &self,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+        timeout: Duration,
+    ) -> crate::Result<Message> {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        match &self.config.rpc_mode {
+            RpcMode::Properties => {
+                tokio::time::timeout_at(
+                    deadline,
+                    self.response_subscription
+                        .get_or_try_init(|| self.subscribe_to_responses()),
+                )
+                .await
+                .map_err(|_| crate::Error::RequestTimeout)??;
+
+                let (id, rx) = self.pending_requests.register();
+
+                let msg = MessageBuilder::new(topic, payload)
+                    .qos_at_least_once()
+                    .response_topic(self.response_topic.as_str())
+                    .correlation_data(id.as_bytes())
+                    .build();
+
+                self.send_request(msg, &id, rx, deadline).await
+            }
+            RpcMode::TopicConvention { prefix } => {
+                let (id, rx) = self.pending_requests.register();
+
+                let response_topic = format!("{}/{}/{}/response", prefix, topic, id);
+
+                // Wait for the subscription to be acknowledged so that the response is not missed
+                tracing::debug!(
+                    parent: &self.span,
+                    topic = %response_topic,
+                    "Subscribing to response topic"
+                );
+                let subscription = SubscriptionBuilder::default()
+                    .topic(response_topic.clone())
+                    .qos_at_least_once()
+                    .build()
+                    .unwrap();
+                let result = match tokio::time::timeout_at(
+                    deadline,
+                    self.transport.subscribe(&subscription),
+                )
+                .await
+                {
+                    Ok(Ok(())) => {
+                        let msg = Message::new(
+                            format!("{}/{}/{}/request", prefix, topic, id),
+                            payload,
+                            1,
+                        );
+                        self.send_request(msg, &id, rx, deadline).await
+                    }
+                    Ok(Err(e)) => {
+                        self.pending_requests.cancel(&id);
+                        Err(e)
+                    }
+                    Err(_) => {
+                        self.pending_requests.cancel(&id);
+                        Err(crate::Error::RequestTimeout)
+                    }
+                };
+
+                if let Err(e) = self.transport.unsubscribe(&response_topic).await {
+                    tracing::warn!(
+                        parent: &self.span,
+                        topic = %response_topic,
+                        error = %e,
+                        "Failed to unsubscribe from response topic"
+                    );
+                }
+
+                result
+            }
+        }
+    }
+
+    async fn send_request(
+        &self,
+        msg: Message,
+        id: &str,
+        rx: oneshot::Receiver<Message>,
+        deadline: tokio::time::Instant,
+    ) -> crate::Result<Message> {
+        if let Err(e) = self.send(msg) {
+            self.pending_requests.cancel(id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout_at(deadline, rx).await {
+            Ok(Ok(msg)) => Ok(msg),
+            Ok(Err(_)) => Err(crate::Error::RequestCancelled),
+            Err(_) => {
+                self.pending_requests.cancel(id);
+                Err(crate::Error::RequestTimeout)
+            }
+        }
+    }
+
+    /// Clear the retained messages on the topics that match a filter.
+    ///
+    /// The filter is subscribed to for `timeout` to discover the topics that have retained
+    /// messages, then an empty retained message is published to each of them. Returns the topics
+    /// that were cleared.
+    ///
+    /// The retained messages received while discovering are not sent on the receiving channel or
+    /// added to the last value cache. If the client is already subscribed to the filter, it is
+    /// subscribed to again with the options of that subscription and stays subscribed.
+    ///
+    /// Returns [`crate::Error::RequestTimeout`] if the subscription is not acknowledged within
+    /// `timeout`.
+    pub async fn clear_retained(
+        &self,
+        filter: &str,
+        timeout: Duration,
+    ) -> crate::Result<Vec<String>> {
+        if !self.transport.is_connected() {
+            return Err(crate::Error::NotConnected);
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.retained_discovery
             .lock()
             .unwrap()
-            .push(subscription.clone());
+            .push((filter.to_owned(), tx.clone()));
 
-        // Subscribe now if the client is connected
-        if self.client.is_connected() {
-            log::debug!(
-                "Adding subscription to active client: {}",
-                subscription.topic
+        tracing::debug!(parent: &self.span, filter, "Discovering retained messages");
+        let default = SubscriptionBuilder::default()
+            .topic(filter.to_owned())
+            .qos_at_least_once()
+            .build()
+            .unwrap();
+        // Subscribe again with the options of an existing subscription to the same filter
+        let existing = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| s.broker_topic() == default.broker_topic())
+            .cloned();
+        let subscribed = existing.is_some();
+        let subscription = existing.unwrap_or(default);
+
+        let result = self
+            .discover_retained(&subscription, subscribed, &mut rx, timeout)
+            .await;
+        self.retained_discovery
+            .lock()
+            .unwrap()
+            .retain(|(_, discovery)| !discovery.same_channel(&tx));
+        let topics = result?;
+
+        for topic in &topics {
+            tracing::debug!(parent: &self.span, topic, "Clearing retained message");
+            self.transport
+                .publish(Message::new_retained(topic.as_str(), Vec::new(), 1))?
+                .await?;
+        }
+
+        Ok(topics.into_iter().collect())
+    }
+
+    /// Subscribe to a filter and collect the topics of the retained messages received within the
+    /// timeout, unsubscribing again unless the client was already subscribed to it.
+    async fn discover_retained(
+        &self,
+        subscription: &Subscription,
+        subscribed: bool,
+        rx: &mut mpsc::UnboundedReceiver<Message>,
+        timeout: Duration,
+    ) -> crate::Result<BTreeSet<String>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let result =
+            match tokio::time::timeout_at(deadline, self.transport.subscribe(subscription)).await {
+                Ok(Ok(())) => {
+                    let mut topics = BTreeSet::new();
+                    while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                        if !msg.payload().is_empty() {
+                            topics.insert(msg.topic().to_owned());
+                        }
+                    }
+                    Ok(topics)
+                }
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(crate::Error::RequestTimeout),
+            };
+
+        if !subscribed {
+            if let Err(e) = self
+                .transport
+                .unsubscribe(&subscription.broker_topic())
+                .await
+            {
+                tracing::warn!(
+                    parent: &self.span,
+                    filter = subscription.topic(),
+                    error = %e,
+                    "Failed to unsubscribe from filter"
+                );
+            }
+        }
+
+        result
+    }
+
+    async fn subscribe_to_responses(&self) -> crate::Result<()> {
+        let subscription = SubscriptionBuilder::default()
+            .topic(self.response_topic.clone())
+            .qos_at_least_once()
+            .build()
+            .unwrap();
+
+        self.cache_subscription(subscription.clone());
+
+        // Wait for the subscription to be acknowledged so that no responses are missed
+        if self.transport.is_connected() {
+            tracing::debug!(
+                parent: &self.span,
+                topic = %subscription.broker_topic(),
+                "Subscribing to response topic"
             );
-            self.client.subscribe(subscription.topic, subscription.qos);
+            self.transport.subscribe(&subscription).await?;
         }
+
+        Ok(())
+    }
+
+    fn cache_subscription(&self, subscription: Subscription) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.push(subscription);
+
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+        self.metrics.set_active_subscriptions(subscriptions.len());
     }
 
     /// Register metrics with a registry.
@@ -90,183 +489,404 @@ impl Client {
     pub fn register_metrics(&self, registry: &mut Registry) {
         let registry = registry.sub_registry_with_prefix(&self.config.metrics_prefix);
 
-        registry.register(
-            "messages",
-            "MQTT messages processed",
-            self.metrics.messages.clone(),
-        );
-
-        registry.register(
-            "connection_events",
-            "MQTT broker connection change events",
-            self.metrics.connection_events.clone(),
-        );
+        self.metrics.prometheus.register(registry);
     }
 
     /// Start the client using the supplied connection options.
+    ///
+    /// The will message of the configuration is used if the options have none.
     pub async fn start(&self, options: ConnectOptions) -> crate::Result<()> {
+        self.start_with(options, None).await
+    }
+
+    /// Start the client, getting the credentials to connect with from a provider.
+    ///
+    /// `options` creates the options to connect with from the current credentials. The client
+    /// reconnects itself when the connection is lost, getting credentials before every attempt, so
+    /// the options should not enable automatic reconnection.
+    pub async fn start_with_credentials<F>(
+        &self,
+        options: F,
+        provider: impl CredentialProvider + 'static,
+    ) -> crate::Result<()>
+    where
+        F: Fn(&Credentials) -> crate::Result<ConnectOptions> + Send + Sync + 'static,
+    {
+        let credentials = provider.credentials().await?;
+        let initial_options = options(&credentials)?;
+
+        let reconnect = Reconnect {
+            options: Box::new(options),
+            provider: Box::new(provider),
+            will_message: self.config.will_message.clone(),
+            notify: Default::default(),
+        };
+
+        self.start_with(initial_options, Some(reconnect)).await
+    }
+
+    async fn start_with(
+        &self,
+        options: ConnectOptions,
+        reconnect: Option<Reconnect>,
+    ) -> crate::Result<()> {
         if self.handle.lock().await.is_some() {
             return Err(crate::Error::ClientAlreadyStarted);
         }
 
-        let client = self.client.clone();
+        // Bind the metrics server first, so that an unusable address fails before connecting
+        #[cfg(feature = "metrics-server")]
+        if let Some(address) = self.config.metrics_server_address {
+            let mut registry = Registry::default();
+            self.register_metrics(&mut registry);
+            let server = {
+                let _span = self.span.enter();
+                self.metrics.serve(address, registry)?
+            };
+            *self.metrics_server.lock().await = Some(server);
+        }
 
         let tx_channel = self.tx_channel.clone();
         let subscriptions = self.subscriptions.clone();
-        #[cfg(feature = "metrics")]
+        let rpc_mode = self.config.rpc_mode.clone();
+        let response_topic = self.response_topic.clone();
+        let pending_requests = self.pending_requests.clone();
+        let last_value_cache = self.last_value_cache.clone();
+        let retained_discovery = self.retained_discovery.clone();
+        let deduplicator = self.config.deduplication.clone().map(Deduplicator::new);
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
         let metrics = self.metrics.clone();
-        client.set_connected_callback(move |c| {
-            log::debug!("Connected");
+        let birth_message = self.config.birth_message.clone();
+        let reconnect_notify = reconnect.as_ref().map(|r| r.notify.clone());
+        let span = self.span.clone();
+        self.transport
+            .set_event_handler(Box::new(move |transport, event| {
+                let _span = span.enter();
 
-            #[cfg(feature = "metrics")]
-            metrics
-                .connection_events
-                .get_or_create(&ConnectionEventLabels::connected())
-                .inc();
+                match event {
+                    TransportEvent::Connected => {
+                        tracing::debug!("Connected");
 
-            if let Err(e) = tx_channel.send(Event::Status(StatusEvent::Connected)) {
-                log::error!("Failed to send event: {}", e);
-            }
+                        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                        metrics.connection_event(ConnectionEventLabels::connected());
 
-            for s in &*subscriptions.lock().unwrap() {
-                c.subscribe(&s.topic, s.qos);
-            }
-        });
+                        if let Err(e) = tx_channel.send(Event::Status(StatusEvent::Connected)) {
+                            tracing::error!(error = %e, "Failed to send event");
+                        }
 
-        let tx_channel = self.tx_channel.clone();
-        #[cfg(feature = "metrics")]
-        let metrics = self.metrics.clone();
-        client.set_disconnected_callback(move |_c, _p, _r| {
-            log::debug!("Disconnected");
+                        for s in &*subscriptions.lock().unwrap() {
+                            tracing::debug!(topic = %s.broker_topic(), qos = s.qos, "Subscribing");
+                            drop(transport.subscribe(s));
+                        }
 
-            #[cfg(feature = "metrics")]
-            metrics
-                .connection_events
-                .get_or_create(&ConnectionEventLabels::disconnected())
-                .inc();
+                        if let Some(birth) = &birth_message {
+                            tracing::debug!(topic = birth.topic(), "Publishing birth message");
+                            if let Err(e) = transport.publish(birth.clone()) {
+                                tracing::error!(error = %e, "Failed to publish birth message");
+                            }
+                        }
+                    }
+                    TransportEvent::Disconnected(reason) => {
+                        tracing::debug!(reason = %reason, "Disconnected");
 
-            if let Err(e) = tx_channel.send(Event::Status(StatusEvent::Disconnected)) {
-                log::error!("Failed to send event: {}", e);
-            }
-        });
+                        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                        metrics.connection_event(ConnectionEventLabels::disconnected());
 
-        let tx_channel = self.tx_channel.clone();
-        #[cfg(feature = "metrics")]
-        let metrics = self.metrics.clone();
-        client.set_connection_lost_callback(move |_c| {
-            log::debug!("Connection lost");
+                        if let Err(e) = tx_channel.send(Event::Status(StatusEvent::Disconnected)) {
+                            tracing::error!(error = %e, "Failed to send event");
+                        }
 
-            #[cfg(feature = "metrics")]
-            metrics
-                .connection_events
-                .get_or_create(&ConnectionEventLabels::lost())
-                .inc();
+                        if let Some(notify) = &reconnect_notify {
+                            notify.notify_one();
+                        }
+                    }
+                    TransportEvent::ConnectionLost => {
+                        tracing::debug!("Connection lost");
 
-            if let Err(e) = tx_channel.send(Event::Status(StatusEvent::Disconnected)) {
-                log::error!("Failed to send event: {}", e);
-            }
-        });
+                        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                        metrics.connection_event(ConnectionEventLabels::lost());
 
-        let tx_channel = self.tx_channel.clone();
-        #[cfg(feature = "metrics")]
-        let metrics = self.metrics.clone();
-        client.set_message_callback(move |_c, msg| {
-            if let Some(msg) = msg {
-                log::debug!("Received message on topic \"{}\"", msg.topic());
-
-                #[cfg(feature = "metrics")]
-                metrics
-                    .messages
-                    .get_or_create(
-                        &MessageLabelsBuilder::default()
-                            .received()
-                            .topic(msg.topic().into())
-                            .build()
-                            .unwrap(),
-                    )
-                    .inc();
+                        if let Err(e) = tx_channel.send(Event::Status(StatusEvent::Disconnected)) {
+                            tracing::error!(error = %e, "Failed to send event");
+                        }
+
+                        if let Some(notify) = &reconnect_notify {
+                            notify.notify_one();
+                        }
+                    }
+                    TransportEvent::Message(msg) => {
+                        #[cfg(feature = "tracing")]
+                        let _span = crate::trace::receive_span(&msg).entered();
+
+                        tracing::debug!(
+                            topic = msg.topic(),
+                            qos = msg.qos(),
+                            retained = msg.retained(),
+                            "Received message"
+                        );
+
+                        // Messages on filters subscribed to for discovering retained messages are
+                        // only delivered to the application if it is subscribed to them
+                        let mut discovering = false;
+                        for (filter, tx) in &*retained_discovery.lock().unwrap() {
+                            if topic::matches(filter, msg.topic()) {
+                                discovering = true;
+                                if msg.retained() {
+                                    drop(tx.send(msg.clone()));
+                                }
+                            }
+                        }
+                        if discovering
+                            && (msg.retained()
+                                || !subscriptions
+                                    .lock()
+                                    .unwrap()
+                                    .iter()
+                                    .any(|s| s.matches(msg.topic())))
+                        {
+                            return;
+                        }
+
+                        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                        metrics.message_received(&msg);
+
+                        if deduplicator.as_ref().is_some_and(|d| d.is_duplicate(&msg)) {
+                            tracing::debug!(topic = msg.topic(), "Dropping duplicate message");
+
+                            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                            metrics.message_deduplicated(&msg);
 
-                if let Err(e) = tx_channel.send(Event::Rx(msg)) {
-                    log::error!("Failed to send event: {}", e);
+                            return;
+                        }
+
+                        // Responses to pending requests are delivered to the requester only
+                        let msg = match rpc_mode.response_id(&response_topic, &msg) {
+                            Some(id) => match pending_requests.resolve(&id, msg) {
+                                Ok(()) => return,
+                                Err(msg) => msg,
+                            },
+                            None => msg,
+                        };
+
+                        if let Some(cache) = &last_value_cache {
+                            cache.update(&msg);
+                        }
+
+                        if let Err(e) = tx_channel.send(Event::Rx(msg)) {
+                            tracing::error!(error = %e, "Failed to send event");
+                        }
+                    }
                 }
-            }
-        });
+            }));
 
-        let response = client.connect(Some(options)).wait()?;
-        log::debug!(
+        let options = with_will(options, &self.config.will_message);
+        let connect = match self.transport.connect(options).await {
+            Ok(connect) => connect,
+            Err(e) => {
+                #[cfg(feature = "metrics-server")]
+                if let Some(server) = self.metrics_server.lock().await.take() {
+                    server.abort();
+                }
+                return Err(e);
+            }
+        };
+        tracing::debug!(
+            parent: &self.span,
+            server_uri = %connect.server_uri,
+            mqtt_version = connect.mqtt_version,
+            session_present = connect.session_present,
             "Using MQTT version {}",
-            response.connect_response().unwrap().mqtt_version
+            connect.mqtt_version
         );
 
+        if let Some(reconnect) = reconnect {
+            *self.reconnect_task.lock().await = Some(tokio::spawn(
+                reconnect
+                    .run(
+                        self.transport.clone(),
+                        self.config.reconnect_interval,
+                        self.config.max_reconnect_interval,
+                    )
+                    .instrument(self.span.clone()),
+            ));
+        }
+
+        let transport = self.transport.clone();
         let tx_channel = self.tx_channel.clone();
         let mut rx_channel = tx_channel.subscribe();
-        #[cfg(feature = "metrics")]
+        let mut rate_limiter = RateLimiter::new(&self.config.rate_limits);
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
         let metrics = self.metrics.clone();
-        *self.handle.lock().await = Some(tokio::spawn(async move {
-            loop {
-                match rx_channel.recv().await {
-                    // Send any messages that are available
-                    Ok(Event::Tx(msg)) => {
-                        log::debug!("Sending message on topic \"{}\"", msg.topic());
-
-                        #[cfg(feature = "metrics")]
-                        let topic = msg.topic().to_string();
-
-                        match client.try_publish(msg) {
-                            Ok(delivery_token) => {
-                                #[cfg(feature = "metrics")]
-                                metrics
-                                    .messages
-                                    .get_or_create(
-                                        &MessageLabelsBuilder::default()
-                                            .sent()
-                                            .topic(topic)
-                                            .build()
-                                            .unwrap(),
-                                    )
-                                    .inc();
-
-                                if let Err(e) = delivery_token.wait() {
-                                    log::error!("Error sending message: {}", e);
-                                }
-                            }
-                            Err(e) => {
-                                #[cfg(feature = "metrics")]
-                                metrics
-                                    .messages
-                                    .get_or_create(
-                                        &MessageLabelsBuilder::default()
-                                            .sent()
-                                            .topic(topic)
-                                            .failure()
-                                            .build()
-                                            .unwrap(),
-                                    )
-                                    .inc();
-
-                                log::error!("Error creating/queuing the message: {}", e)
+
+        // Messages are moved to an unbounded queue as soon as they are sent, so that they are not
+        // lost from the channel while publishing is delayed by a rate limit
+        let (queue_tx, mut queue_rx) = mpsc::unbounded_channel();
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+        let queue_metrics = self.metrics.clone();
+        tokio::spawn(
+            async move {
+                loop {
+                    match rx_channel.recv().await {
+                        Ok(event @ (Event::Tx(_) | Event::Stop)) => {
+                            let stop = matches!(event, Event::Stop);
+                            if queue_tx.send(event).is_err() || stop {
+                                return;
                             }
                         }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::warn!(skipped, "Receive error, events were skipped");
+
+                            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                            queue_metrics.messages_dequeued(skipped as usize);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
                     }
-                    // Exit if requested
-                    Ok(Event::Stop) => {
-                        log::debug!("Stopped");
-                        return;
-                    }
-                    Err(e) => {
-                        log::warn!("Receive error: {}", e);
+                }
+            }
+            .instrument(self.span.clone()),
+        );
+
+        *self.handle.lock().await = Some(tokio::spawn(
+            async move {
+                while let Some(event) = queue_rx.recv().await {
+                    match event {
+                        // Send any messages that are available
+                        Event::Tx(msg) => {
+                            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                            metrics.messages_dequeued(1);
+
+                            #[cfg(feature = "tracing")]
+                            let span = crate::trace::publish_span(&msg);
+                            #[cfg(not(feature = "tracing"))]
+                            let span = tracing::Span::current();
+
+                            async {
+                                let admission = rate_limiter
+                                    .admit(msg.topic(), |limit| {
+                                        tracing::warn!(
+                                            filter = ?limit.topic,
+                                            policy = ?limit.policy,
+                                            "Publishing over rate limit"
+                                        );
+
+                                        let event = StatusEvent::RateLimited {
+                                            topic: limit.topic.clone(),
+                                            policy: limit.policy,
+                                        };
+                                        if let Err(e) = tx_channel.send(Event::Status(event)) {
+                                            tracing::error!(error = %e, "Failed to send event");
+                                        }
+                                    })
+                                    .await;
+
+                                #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                                if admission != Admission::Allowed {
+                                    metrics.message_rate_limited(
+                                        &msg,
+                                        admission == Admission::Dropped,
+                                    );
+                                }
+
+                                if admission == Admission::Dropped {
+                                    tracing::debug!(
+                                        topic = msg.topic(),
+                                        "Dropping message over rate limit"
+                                    );
+                                    return;
+                                }
+
+                                tracing::debug!(
+                                    topic = msg.topic(),
+                                    qos = msg.qos(),
+                                    retained = msg.retained(),
+                                    "Sending message"
+                                );
+
+                                #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+                                let publish = metrics.publish(&msg);
+
+                                match transport.publish(msg) {
+                                    Ok(delivery) => {
+                                        #[cfg(any(
+                                            feature = "metrics",
+                                            feature = "opentelemetry"
+                                        ))]
+                                        publish.queued();
+
+                                        let result = delivery.await;
+
+                                        #[cfg(any(
+                                            feature = "metrics",
+                                            feature = "opentelemetry"
+                                        ))]
+                                        publish.delivered(result.is_ok());
+
+                                        if let Err(e) = result {
+                                            tracing::error!(error = %e, "Error sending message");
+                                        }
+                                    }
+                                    Err(e) => {
+                                        #[cfg(any(
+                                            feature = "metrics",
+                                            feature = "opentelemetry"
+                                        ))]
+                                        publish.failed();
+
+                                        tracing::error!(
+                                            error = %e,
+                                            "Error creating/queuing the message"
+                                        )
+                                    }
+                                }
+                            }
+                            .instrument(span)
+                            .await;
+                        }
+                        // Exit if requested
+                        Event::Stop => {
+                            tracing::debug!("Stopped");
+                            return;
+                        }
+                        _ => {}
                     }
-                    _ => {}
                 }
             }
-        }));
+            .instrument(self.span.clone()),
+        ));
 
         Ok(())
     }
 
     /// Request for the client to be stopped and wait for it to terminate.
     pub async fn stop(&self) -> crate::Result<()> {
-        log::trace!("Stopping client");
+        tracing::trace!(parent: &self.span, "Stopping client");
+
+        #[cfg(feature = "metrics-server")]
+        if let Some(server) = self.metrics_server.lock().await.take() {
+            server.abort();
+        }
+
+        if let Some(reconnect) = self.reconnect_task.lock().await.take() {
+            reconnect.abort();
+        }
+
+        if let Some(shutdown) = &self.config.shutdown_message {
+            if self.transport.is_connected() {
+                tracing::debug!(
+                    parent: &self.span,
+                    topic = shutdown.topic(),
+                    "Publishing shutdown message"
+                );
+                let result = match self.transport.publish(shutdown.clone()) {
+                    Ok(delivery) => delivery.await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    tracing::error!(parent: &self.span, error = %e, "Failed to publish shutdown message");
+                }
+            }
+        }
 
         // Send termination request
         self.tx_channel.send(Event::Stop)?;
@@ -278,3 +898,330 @@ impl Client {
         }
     }
 }
+
+/// Filters subscribed to by [`Client::clear_retained`], with the channel receiving the retained
+/// messages that match them.
+type RetainedDiscovery = Arc<Mutex<Vec<(String, mpsc::UnboundedSender<Message>)>>>;
+
+/// Creates the options to connect with from credentials.
+type ConnectOptionsFn = Box<dyn Fn(&Credentials) -> crate::Result<ConnectOptions> + Send + Sync>;
+
+/// Reconnects a client with credentials from a provider.
+struct Reconnect {
+    options: ConnectOptionsFn,
+    provider: Box<dyn CredentialProvider>,
+    will_message: Option<Message>,
+
+    /// Notified when the connection is lost.
+    notify: Arc<Notify>,
+}
+
+impl Reconnect {
+    async fn run(
+        self,
+        transport: Arc<dyn Transport>,
+        min_interval: Duration,
+        max_interval: Duration,
+    ) {
+        loop {
+            self.notify.notified().await;
+
+            let mut interval = min_interval;
+            loop {
+                tokio::time::sleep(interval).await;
+
+                if transport.is_connected() {
+                    break;
+                }
+
+                tracing::debug!("Reconnecting");
+                match self.connect(&*transport).await {
+                    Ok(()) => break,
+                    Err(e) => tracing::warn!(error = %e, "Failed to reconnect"),
+                }
+
+                interval = (interval * 2).min(max_interval);
+            }
+        }
+    }
+
+    async fn connect(&self, transport: &dyn Transport) -> crate::Result<()> {
+        let credentials = self.provider.credentials().await?;
+        let options = with_will((self.options)(&credentials)?, &self.will_message);
+        transport.connect(options).await?;
+        Ok(())
+    }
+}
+
+/// Use the will message of the configuration if the options to connect with have none.
+fn with_will(mut options: ConnectOptions, will_message: &Option<Message>) -> ConnectOptions {
+    if options.will_message.is_none() {
+        options.will_message = will_message.clone();
+    }
+    options
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::{
+        transport::{start_client, MockTransport},
+        ClientConfigBuilder,
+    };
+
+    /// Clear the retained messages on a filter while the broker sends those on `topics`.
+    async fn clear_retained(
+        client: &Client,
+        transport: &MockTransport,
+        topics: &[&str],
+    ) -> Vec<String> {
+        let task = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .clear_retained("a/#", Duration::from_millis(100))
+                    .await
+            })
+        };
+        while client.retained_discovery.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        for topic in topics {
+            transport.inject_message(Message::new_retained(*topic, "1", 1));
+        }
+
+        task.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn clear_retained_new_subscription() {
+        let (client, transport) = start_client(Default::default()).await;
+
+        let cleared = clear_retained(&client, &transport, &["a/2", "a/1"]).await;
+        assert_eq!(cleared, ["a/1", "a/2"]);
+
+        let published = transport.wait_for_published(2).await;
+        assert!(published
+            .iter()
+            .all(|msg| msg.retained() && msg.payload().is_empty()));
+
+        // The filter is unsubscribed from after discovering
+        assert!(transport.subscriptions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clear_retained_existing_subscription() {
+        let subscription = SubscriptionBuilder::default()
+            .topic("a/#".to_owned())
+            .qos_exactly_once()
+            .no_local(true)
+            .subscription_id(7)
+            .build()
+            .unwrap();
+        let config = ClientConfigBuilder::default()
+            .subscriptions(vec![subscription])
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+
+        let cleared = clear_retained(&client, &transport, &["a/1"]).await;
+        assert_eq!(cleared, ["a/1"]);
+
+        // The existing subscription is kept with its own options
+        let subscriptions = transport.subscriptions();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].qos(), 2);
+        assert!(subscriptions[0].no_local());
+        assert_eq!(subscriptions[0].subscription_id(), Some(7));
+    }
+
+    #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+    #[tokio::test]
+    async fn queue_depth_lagged() {
+        let config = ClientConfigBuilder::default()
+            .channel_size(4usize)
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+
+        // The publish loop does not run until the test yields, so it lags behind
+        for i in 0..10 {
+            client.send(Message::new(i.to_string(), "1", 0)).unwrap();
+        }
+        assert_eq!(client.metrics.state.queue_depth(), 10);
+
+        transport.wait_for_published(4).await;
+        assert_eq!(client.metrics.state.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn will_message() {
+        let config = ClientConfigBuilder::default()
+            .will_message(Message::new("will", "config", 1))
+            .build()
+            .unwrap();
+        let (_client, transport) = start_client(config.clone()).await;
+        let will = transport.connect_options().unwrap().will_message.unwrap();
+        assert_eq!(will.payload(), b"config");
+
+        // A will message in the options to connect with is kept
+        let transport = MockTransport::new("client");
+        let client = Client::with_transport(transport.clone(), config);
+        client
+            .start(
+                crate::ConnectOptionsBuilder::default()
+                    .will_message(Message::new("will", "options", 1))
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let will = transport.connect_options().unwrap().will_message.unwrap();
+        assert_eq!(will.payload(), b"options");
+    }
+
+    #[tokio::test]
+    async fn birth_message() {
+        let config = ClientConfigBuilder::default()
+            .birth_message(Message::new("birth", "online", 1))
+            .build()
+            .unwrap();
+        let (_client, transport) = start_client(config).await;
+        assert_eq!(transport.wait_for_published(1).await[0].topic(), "birth");
+
+        // The birth message is published again on every connection
+        transport.simulate_connection_lost();
+        transport.simulate_connect();
+        let published = transport.wait_for_published(2).await;
+        assert_eq!(published[1].topic(), "birth");
+    }
+
+    #[tokio::test]
+    async fn shutdown_message() {
+        let config = ClientConfigBuilder::default()
+            .shutdown_message(Message::new("shutdown", "offline", 1))
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+        assert!(transport.published().is_empty());
+
+        client.stop().await.unwrap();
+        let published = transport.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].topic(), "shutdown");
+        assert_eq!(published[0].payload(), b"offline");
+    }
+
+    /// Provider of numbered passwords, failing the attempts listed in `fail`.
+    #[derive(Clone, Default)]
+    struct CountingProvider {
+        count: Arc<std::sync::atomic::AtomicUsize>,
+        fail: Vec<usize>,
+    }
+
+    impl CredentialProvider for CountingProvider {
+        fn credentials(&self) -> crate::CredentialsFuture<'_> {
+            let n = self
+                .count
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                + 1;
+            let fail = self.fail.contains(&n);
+            Box::pin(async move {
+                if fail {
+                    return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+                }
+                Ok(Credentials {
+                    username: Some("me".into()),
+                    password: Some(n.to_string()),
+                })
+            })
+        }
+    }
+
+    /// Start a client with credentials from `provider`, reconnecting quickly.
+    async fn start_with_provider(provider: CountingProvider) -> (Client, MockTransport) {
+        let config = ClientConfigBuilder::default()
+            .reconnect_interval(Duration::from_millis(10))
+            .max_reconnect_interval(Duration::from_millis(40))
+            .will_message(Message::new("will", "offline", 1))
+            .build()
+            .unwrap();
+        let transport = MockTransport::new("client");
+        let client = Client::with_transport(transport.clone(), config);
+        client
+            .start_with_credentials(
+                |credentials| {
+                    Ok(crate::ConnectOptionsBuilder::default()
+                        .credentials(credentials.clone())
+                        .build()
+                        .unwrap())
+                },
+                provider,
+            )
+            .await
+            .unwrap();
+        (client, transport)
+    }
+
+    /// Wait until the client has reconnected after losing the connection.
+    async fn wait_for_reconnect(transport: &MockTransport) -> ConnectOptions {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !transport.is_connected() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        transport.connect_options().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconnect_credentials() {
+        let provider = CountingProvider::default();
+        let (_client, transport) = start_with_provider(provider.clone()).await;
+
+        let options = transport.connect_options().unwrap();
+        assert_eq!(options.credentials.password.as_deref(), Some("1"));
+        assert_eq!(options.will_message.unwrap().topic(), "will");
+
+        // Credentials are fetched again for every reconnection
+        for password in ["2", "3"] {
+            transport.simulate_connection_lost();
+            let options = wait_for_reconnect(&transport).await;
+            assert_eq!(options.credentials.password.as_deref(), Some(password));
+            assert_eq!(options.credentials.username.as_deref(), Some("me"));
+            assert_eq!(options.will_message.unwrap().topic(), "will");
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_retry() {
+        let provider = CountingProvider {
+            fail: vec![2, 3],
+            ..Default::default()
+        };
+        let (_client, transport) = start_with_provider(provider.clone()).await;
+
+        // Attempts that fail to get credentials are retried
+        transport.simulate_connection_lost();
+        let options = wait_for_reconnect(&transport).await;
+        assert_eq!(options.credentials.password.as_deref(), Some("4"));
+        assert_eq!(provider.count.load(std::sync::atomic::Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn start_credentials_error() {
+        let provider = CountingProvider {
+            fail: vec![1],
+            ..Default::default()
+        };
+        let transport = MockTransport::new("client");
+        let client = Client::with_transport(transport.clone(), Default::default());
+        let result = client
+            .start_with_credentials(|_| Ok(Default::default()), provider)
+            .await;
+
+        assert!(matches!(result, Err(crate::Error::IoError(_))));
+        assert!(transport.connect_options().is_none());
+    }
+}
@@ -1,23 +1,239 @@
+#[cfg(doc)]
+use crate::LastValueCache;
+use crate::{ConnectOptionsBuilder, Deduplication, Message, RateLimit, RpcMode, Subscription};
 use derive_builder::Builder;
+#[cfg(feature = "metrics")]
+use prometheus_client::metrics::histogram::exponential_buckets;
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+use regex::Regex;
+#[cfg(feature = "metrics-server")]
+use std::net::SocketAddr;
+use std::time::Duration;
 
 /// Miscellaneous client configuration.
 #[derive(Builder, Debug, Clone)]
 #[builder(default)]
+#[cfg_attr(feature = "config-file", derive(serde::Deserialize), serde(default))]
 pub struct ClientConfig {
     /// Size of the Tokio channel.
     pub(crate) channel_size: usize,
 
+    /// Subscriptions the client starts with, which are made on every connection.
+    pub(crate) subscriptions: Vec<Subscription>,
+
+    /// Time to wait before the first attempt to reconnect, when the client reconnects itself.
+    ///
+    /// The time doubles after each failed attempt, up to `max_reconnect_interval`.
+    #[cfg_attr(feature = "config-file", serde(skip))]
+    pub(crate) reconnect_interval: Duration,
+
+    /// Longest time to wait between attempts to reconnect, when the client reconnects itself.
+    #[cfg_attr(feature = "config-file", serde(skip))]
+    pub(crate) max_reconnect_interval: Duration,
+
+    /// Message published every time the client connects.
+    #[builder(setter(strip_option))]
+    #[cfg_attr(
+        feature = "config-file",
+        serde(deserialize_with = "deserialize_message")
+    )]
+    pub(crate) birth_message: Option<Message>,
+
+    /// Message published by the broker when the client disconnects unexpectedly.
+    ///
+    /// The will message is part of the options to connect with, it is used when the client is
+    /// started with options that have none.
+    #[builder(setter(strip_option))]
+    #[cfg_attr(
+        feature = "config-file",
+        serde(deserialize_with = "deserialize_message")
+    )]
+    pub(crate) will_message: Option<Message>,
+
+    /// Message published when the client is stopped.
+    #[builder(setter(strip_option))]
+    #[cfg_attr(
+        feature = "config-file",
+        serde(deserialize_with = "deserialize_message")
+    )]
+    pub(crate) shutdown_message: Option<Message>,
+
+    /// Keep the latest message received on each topic, see [`LastValueCache`].
+    pub(crate) last_value_cache: bool,
+
+    /// Drop QoS 1 messages that are redelivered by the broker.
+    #[builder(setter(strip_option))]
+    pub(crate) deduplication: Option<Deduplication>,
+
+    /// Limits of the rate at which messages are published.
+    pub(crate) rate_limits: Vec<RateLimit>,
+
+    /// How requests are associated with their responses.
+    pub(crate) rpc_mode: RpcMode,
+
+    /// Prefix of the topic on which responses to requests are received when using
+    /// [`RpcMode::Properties`].
+    ///
+    /// The client ID is appended to form the response topic.
+    pub(crate) response_topic_prefix: String,
+
     /// Metric name prefix
-    #[cfg(feature = "metrics")]
+    #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
     pub(crate) metrics_prefix: String,
+
+    /// Upper bounds (in seconds) of the buckets of the publish latency histogram.
+    #[cfg(feature = "metrics")]
+    pub(crate) publish_latency_buckets: Vec<f64>,
+
+    /// How message topics are represented in metric labels.
+    #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+    pub(crate) topic_label: TopicLabel,
+
+    /// Address to serve metrics and health over HTTP on, while the client is started.
+    ///
+    /// Metrics are served on `/metrics` and the connection state on `/healthz`.
+    #[cfg(feature = "metrics-server")]
+    pub(crate) metrics_server_address: Option<SocketAddr>,
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             channel_size: 16,
-            #[cfg(feature = "metrics")]
+            subscriptions: Vec::new(),
+            reconnect_interval: Duration::from_secs(1),
+            max_reconnect_interval: Duration::from_secs(30),
+            birth_message: None,
+            will_message: None,
+            shutdown_message: None,
+            last_value_cache: false,
+            deduplication: None,
+            rate_limits: Vec::new(),
+            rpc_mode: RpcMode::default(),
+            response_topic_prefix: "response".into(),
+            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
             metrics_prefix: "mqtt".into(),
+            #[cfg(feature = "metrics")]
+            publish_latency_buckets: exponential_buckets(0.001, 2.0, 14).collect(),
+            #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+            topic_label: TopicLabel::default(),
+            #[cfg(feature = "metrics-server")]
+            metrics_server_address: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Set the will message, if configured, in the options to connect with.
+    ///
+    /// The client does this when it is started with options that have no will message, this is
+    /// only needed to use the options with another client.
+    pub fn apply_will<'a>(
+        &self,
+        options: &'a mut ConnectOptionsBuilder,
+    ) -> &'a mut ConnectOptionsBuilder {
+        match &self.will_message {
+            Some(will) => options.will_message(will.clone()),
+            None => options,
+        }
+    }
+}
+
+/// Representation of a message in a configuration file.
+#[cfg(feature = "config-file")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageConfig {
+    topic: String,
+    payload: String,
+    #[serde(default)]
+    qos: i32,
+    #[serde(default)]
+    retain: bool,
+}
+
+#[cfg(feature = "config-file")]
+fn deserialize_message<'de, D>(deserializer: D) -> Result<Option<Message>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let config: Option<MessageConfig> = serde::Deserialize::deserialize(deserializer)?;
+
+    Ok(config.map(|m| {
+        if m.retain {
+            Message::new_retained(m.topic, m.payload, m.qos)
+        } else {
+            Message::new(m.topic, m.payload, m.qos)
         }
+    }))
+}
+
+/// How the topic of a message is represented in metric labels.
+///
+/// Using the topic as is creates a metric series for every topic, which may be unbounded when
+/// topics contain identifiers.
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(try_from = "TopicLabelConfig")
+)]
+pub enum TopicLabel {
+    /// Use the topic as is.
+    #[default]
+    Topic,
+
+    /// Use the filter of the first subscription that matches the topic.
+    ///
+    /// Topics that match no subscription are labelled with an empty string.
+    SubscriptionFilter,
+
+    /// Replace the first match of a regular expression in the topic.
+    ///
+    /// `replacement` may refer to capture groups, see [`Regex::replace`]. Topics that do not match
+    /// are labelled with `"other"`, so that they do not each get their own label.
+    Regex { regex: Regex, replacement: String },
+
+    /// Use only the first levels of the topic, up to the given depth.
+    PrefixDepth(usize),
+
+    /// Label all topics with an empty string.
+    Drop,
+}
+
+/// Representation of a [`TopicLabel`] in a configuration file.
+#[cfg(all(
+    feature = "config-file",
+    any(feature = "metrics", feature = "opentelemetry")
+))]
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TopicLabelConfig {
+    Topic,
+    SubscriptionFilter,
+    Regex { regex: String, replacement: String },
+    PrefixDepth(usize),
+    Drop,
+}
+
+#[cfg(all(
+    feature = "config-file",
+    any(feature = "metrics", feature = "opentelemetry")
+))]
+impl TryFrom<TopicLabelConfig> for TopicLabel {
+    type Error = regex::Error;
+
+    fn try_from(config: TopicLabelConfig) -> Result<Self, Self::Error> {
+        Ok(match config {
+            TopicLabelConfig::Topic => Self::Topic,
+            TopicLabelConfig::SubscriptionFilter => Self::SubscriptionFilter,
+            TopicLabelConfig::Regex { regex, replacement } => Self::Regex {
+                regex: Regex::new(&regex)?,
+                replacement,
+            },
+            TopicLabelConfig::PrefixDepth(depth) => Self::PrefixDepth(depth),
+            TopicLabelConfig::Drop => Self::Drop,
+        })
     }
 }
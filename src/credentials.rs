@@ -0,0 +1,68 @@
+#[cfg(feature = "file-credentials")]
+mod file;
+
+use std::{fmt, future::Future, pin::Pin};
+
+#[cfg(feature = "file-credentials")]
+pub use self::file::FileCredentialProvider;
+
+/// Future returned by a [`CredentialProvider`].
+pub type CredentialsFuture<'a> =
+    Pin<Box<dyn Future<Output = crate::Result<Credentials>> + Send + 'a>>;
+
+/// Credentials to authenticate with the broker.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Source of the credentials used to connect to the broker.
+///
+/// A client started with [`Client::start_with_credentials`](crate::Client::start_with_credentials)
+/// gets credentials from its provider before every attempt to connect, so short lived
+/// credentials such as tokens can be rotated.
+pub trait CredentialProvider: Send + Sync {
+    /// Get the credentials to use for the next connection.
+    fn credentials(&self) -> CredentialsFuture<'_>;
+}
+
+impl CredentialProvider for Credentials {
+    fn credentials(&self) -> CredentialsFuture<'_> {
+        Box::pin(async move { Ok(self.clone()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug() {
+        let credentials = Credentials {
+            username: Some("me".into()),
+            password: Some("secret".into()),
+        };
+        let debug = format!("{:?}", credentials);
+        assert!(debug.contains("me"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn static_credentials() {
+        let credentials = Credentials {
+            username: Some("me".into()),
+            password: None,
+        };
+        assert_eq!(credentials.credentials().await.unwrap(), credentials);
+    }
+}
@@ -0,0 +1,124 @@
+use super::{CredentialProvider, Credentials, CredentialsFuture};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// [`CredentialProvider`] that reads the password (e.g. a token) from a file.
+///
+/// The directory containing the file is watched, the file is read again after it changes, so
+/// tokens written by another process (such as a sidecar refreshing a mounted secret) are used for
+/// the next connection.
+pub struct FileCredentialProvider {
+    username: Option<String>,
+    path: PathBuf,
+
+    /// Password read from the file, cleared when the file changes.
+    password: Arc<Mutex<Option<String>>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl FileCredentialProvider {
+    /// Create a provider that uses the contents of the file at `path`, without trailing
+    /// whitespace, as the password.
+    pub fn new(path: impl Into<PathBuf>, username: Option<String>) -> crate::Result<Self> {
+        let path = path.into();
+        let password = Arc::new(Mutex::new(Some(read(&path)?)));
+
+        // Watch the directory rather than the file, as the file may be replaced
+        let p = password.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+            let _: notify::Event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Error watching credentials file");
+                    return;
+                }
+            };
+            p.lock().unwrap().take();
+        })?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            username,
+            path,
+            password,
+            _watcher: watcher,
+        })
+    }
+}
+
+impl CredentialProvider for FileCredentialProvider {
+    fn credentials(&self) -> CredentialsFuture<'_> {
+        Box::pin(async move {
+            let mut password = self.password.lock().unwrap();
+
+            let password = match &*password {
+                Some(password) => password.clone(),
+                None => {
+                    tracing::debug!(path = %self.path.display(), "Reading credentials file");
+                    password.insert(read(&self.path)?).clone()
+                }
+            };
+
+            Ok(Credentials {
+                username: self.username.clone(),
+                password: Some(password),
+            })
+        })
+    }
+}
+
+fn read(path: &Path) -> crate::Result<String> {
+    Ok(std::fs::read_to_string(path)?.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn reload() {
+        let dir = std::env::temp_dir().join(format!("credentials-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token");
+        std::fs::write(&path, "old\n").unwrap();
+
+        let provider = FileCredentialProvider::new(&path, Some("me".into())).unwrap();
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(credentials.username.as_deref(), Some("me"));
+        assert_eq!(credentials.password.as_deref(), Some("old"));
+
+        // Replace the file, as a sidecar refreshing a secret would
+        let tmp = dir.join("token.tmp");
+        std::fs::write(&tmp, "new").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+
+        let password = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let credentials = provider.credentials().await.unwrap();
+                if credentials.password.as_deref() != Some("old") {
+                    return credentials.password;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(password.as_deref(), Some("new"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join("credentials-missing/token");
+        assert!(FileCredentialProvider::new(path, None).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::Message;
+use derive_builder::Builder;
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Suppression of QoS 1 messages that are received more than once.
+///
+/// The key of every message is remembered, a message is dropped when a message with the same key
+/// was received on the same topic within the window and it is either flagged by the broker as a
+/// redelivery ([`Message::duplicate`]) or keyed by its correlation data. A message that repeats
+/// the payload of an earlier message without being flagged, such as a state changing back, is
+/// not dropped. Only the most recent `capacity` keys are remembered.
+///
+/// The paho backend does not expose the redelivery flag, so only messages keyed by their
+/// correlation data are dropped when using it. A warning is logged when a client using it is
+/// created with [`DeduplicationKey::PayloadHash`], which then drops no messages.
+#[derive(Builder, Debug, Clone)]
+#[builder(default)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Deduplication {
+    /// How messages are identified.
+    pub(crate) key: DeduplicationKey,
+
+    /// How long the key of a message is remembered.
+    #[cfg_attr(feature = "config-file", serde(with = "humantime_serde"))]
+    pub(crate) window: Duration,
+
+    /// Maximum number of keys remembered, the oldest are forgotten first.
+    pub(crate) capacity: usize,
+}
+
+impl Default for Deduplication {
+    fn default() -> Self {
+        Self {
+            key: DeduplicationKey::default(),
+            window: Duration::from_secs(60),
+            capacity: 1024,
+        }
+    }
+}
+
+/// How a message is identified for [`Deduplication`].
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum DeduplicationKey {
+    /// Use the MQTT v5 correlation data, or a hash of the payload for messages without.
+    #[default]
+    CorrelationData,
+
+    /// Use a hash of the payload.
+    PayloadHash,
+}
+
+/// Keys of the recently received messages.
+pub(crate) struct Deduplicator {
+    config: Deduplication,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    keys: HashSet<u64>,
+    received: VecDeque<(Instant, u64)>,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(config: Deduplication) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Check if a message was already received, remembering it if not.
+    pub(crate) fn is_duplicate(&self, msg: &Message) -> bool {
+        if msg.qos() != 1 {
+            return false;
+        }
+
+        let (key, correlated) = self.key(msg);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        // Forget the keys that are outside of the window
+        while let Some(&(received, key)) = state.received.front() {
+            if now.duration_since(received) < self.config.window {
+                break;
+            }
+            state.received.pop_front();
+            state.keys.remove(&key);
+        }
+
+        if state.keys.contains(&key) {
+            // Only redeliveries are dropped, unless the key identifies the message
+            return msg.duplicate() || correlated;
+        }
+
+        if state.received.len() >= self.config.capacity {
+            if let Some((_, key)) = state.received.pop_front() {
+                state.keys.remove(&key);
+            }
+        }
+        state.keys.insert(key);
+        state.received.push_back((now, key));
+
+        false
+    }
+
+    /// Get the key of a message, and if it is the correlation data of the message.
+    fn key(&self, msg: &Message) -> (u64, bool) {
+        let mut hasher = DefaultHasher::new();
+        msg.topic().hash(&mut hasher);
+
+        let correlation_data = match self.config.key {
+            DeduplicationKey::CorrelationData => msg.properties().correlation_data.as_ref(),
+            DeduplicationKey::PayloadHash => None,
+        };
+        let correlated = match correlation_data {
+            Some(data) => {
+                0u8.hash(&mut hasher);
+                data.hash(&mut hasher);
+                true
+            }
+            None => {
+                1u8.hash(&mut hasher);
+                msg.payload().hash(&mut hasher);
+                false
+            }
+        };
+
+        (hasher.finish(), correlated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBuilder;
+
+    fn deduplicator(key: DeduplicationKey) -> Deduplicator {
+        Deduplicator::new(Deduplication {
+            key,
+            ..Default::default()
+        })
+    }
+
+    fn redelivered(mut msg: Message) -> Message {
+        msg.duplicate = true;
+        msg
+    }
+
+    #[test]
+    fn redelivery() {
+        let d = deduplicator(DeduplicationKey::PayloadHash);
+        let msg = Message::new("a", "payload", 1);
+
+        assert!(!d.is_duplicate(&msg));
+        assert!(d.is_duplicate(&redelivered(msg.clone())));
+
+        // Only the same payload on the same topic is a duplicate
+        assert!(!d.is_duplicate(&redelivered(Message::new("a", "other", 1))));
+        assert!(!d.is_duplicate(&redelivered(Message::new("b", "payload", 1))));
+    }
+
+    #[test]
+    fn repeated_payload() {
+        let d = deduplicator(DeduplicationKey::PayloadHash);
+
+        for payload in ["open", "closed", "open"] {
+            assert!(!d.is_duplicate(&Message::new("door", payload, 1)));
+        }
+    }
+
+    #[test]
+    fn correlation_data() {
+        let d = deduplicator(DeduplicationKey::CorrelationData);
+        let msg = |payload: &str, id: &[u8]| {
+            MessageBuilder::new("a", payload)
+                .qos_at_least_once()
+                .correlation_data(id)
+                .build()
+        };
+
+        // Correlation data identifies the message, so it is a duplicate without the flag
+        assert!(!d.is_duplicate(&msg("one", b"1")));
+        assert!(d.is_duplicate(&msg("two", b"1")));
+        assert!(!d.is_duplicate(&msg("one", b"2")));
+
+        // Messages without correlation data use the payload
+        assert!(!d.is_duplicate(&Message::new("a", "one", 1)));
+        assert!(!d.is_duplicate(&Message::new("a", "one", 1)));
+        assert!(d.is_duplicate(&redelivered(Message::new("a", "one", 1))));
+
+        // Correlation data is ignored when using the payload
+        let d = deduplicator(DeduplicationKey::PayloadHash);
+        assert!(!d.is_duplicate(&msg("one", b"1")));
+        assert!(!d.is_duplicate(&msg("two", b"1")));
+    }
+
+    #[test]
+    fn qos() {
+        let d = deduplicator(DeduplicationKey::PayloadHash);
+
+        for qos in [0, 2] {
+            let msg = Message::new("a", "payload", qos);
+            assert!(!d.is_duplicate(&msg));
+            assert!(!d.is_duplicate(&redelivered(msg)));
+        }
+    }
+
+    #[test]
+    fn window() {
+        let d = Deduplicator::new(Deduplication {
+            key: DeduplicationKey::PayloadHash,
+            window: Duration::from_millis(50),
+            capacity: 1024,
+        });
+        let msg = Message::new("a", "payload", 1);
+
+        assert!(!d.is_duplicate(&msg));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!d.is_duplicate(&redelivered(msg)));
+    }
+
+    #[test]
+    fn capacity() {
+        let d = Deduplicator::new(Deduplication {
+            key: DeduplicationKey::PayloadHash,
+            capacity: 2,
+            ..Default::default()
+        });
+
+        for payload in ["1", "2", "3"] {
+            assert!(!d.is_duplicate(&Message::new("a", payload, 1)));
+        }
+
+        // The oldest key is forgotten
+        assert!(!d.is_duplicate(&redelivered(Message::new("a", "1", 1))));
+        assert!(d.is_duplicate(&redelivered(Message::new("a", "3", 1))));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn client() {
+        use crate::{transport::start_client, ClientConfigBuilder, Event};
+
+        let config = ClientConfigBuilder::default()
+            .deduplication(Deduplication {
+                key: DeduplicationKey::PayloadHash,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+        let mut rx = client.rx_channel();
+
+        transport.inject_message(Message::new("door", "open", 1));
+        transport.inject_message(redelivered(Message::new("door", "open", 1)));
+        transport.inject_message(Message::new("door", "closed", 1));
+        transport.inject_message(Message::new("door", "open", 1));
+
+        let mut payloads = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let Event::Rx(msg) = event {
+                payloads.push(msg.payload_str().into_owned());
+            }
+        }
+        assert_eq!(payloads, ["open", "closed", "open"]);
+    }
+}
@@ -6,18 +6,67 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Error type for fallible operations in this crate.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[cfg(feature = "paho")]
     #[error("MQTT error")]
     MqttError(#[from] paho_mqtt::Error),
 
+    #[error("IO error")]
+    IoError(#[from] std::io::Error),
+
     #[error("Task join error")]
     JoinError(#[from] tokio::task::JoinError),
 
     #[error("Channel error")]
     ChannelError(#[from] tokio::sync::broadcast::error::SendError<Event>),
 
+    #[cfg(feature = "metrics-server")]
+    #[error("Metrics server error")]
+    MetricsServerError(#[from] hyper::Error),
+
     #[error("Client was requested to start but is already started")]
     ClientAlreadyStarted,
 
     #[error("Client was requested to stop but is already stopped")]
     ClientAlreadyStopped,
+
+    #[error("Client is not connected")]
+    NotConnected,
+
+    #[error("No response was received to a request before the timeout")]
+    RequestTimeout,
+
+    #[error("Request was cancelled before a response was received")]
+    RequestCancelled,
+
+    #[error("No response topic could be determined for request on topic \"{0}\"")]
+    NoResponseTopic(String),
+
+    #[error("Cannot read TLS file \"{}\"", path.display())]
+    TlsFileError {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid TLS configuration: {0}")]
+    InvalidTlsConfig(String),
+
+    #[cfg(feature = "config-file")]
+    #[error("TOML error")]
+    TomlError(#[from] toml::de::Error),
+
+    #[cfg(feature = "config-file")]
+    #[error("YAML error")]
+    YamlError(#[from] serde_yaml::Error),
+
+    #[cfg(feature = "config-file")]
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[cfg(feature = "file-credentials")]
+    #[error("File watch error")]
+    WatchError(#[from] notify::Error),
+
+    #[cfg(feature = "rumqttc")]
+    #[error("rumqttc error: {0}")]
+    RumqttcError(String),
 }
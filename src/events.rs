@@ -1,4 +1,4 @@
-use paho_mqtt::Message;
+use crate::{Message, RateLimitPolicy};
 
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -23,4 +23,13 @@ pub enum StatusEvent {
 
     /// Client has disconnected from (or lost connection to) the MQTT broker.
     Disconnected,
+
+    /// Messages started to be published over a rate limit.
+    RateLimited {
+        /// Topic filter of the limit, `None` for a limit of all messages.
+        topic: Option<String>,
+
+        /// What happens to the messages over the limit.
+        policy: RateLimitPolicy,
+    },
 }
@@ -1,21 +1,75 @@
 //! MQTT client that communicates over Tokio channels.
 
+#[cfg(feature = "paho")]
 pub use paho_mqtt;
 
 mod events;
 pub use self::events::{Event, StatusEvent};
 
+mod message;
+pub use self::message::{Message, MessageBuilder, MessageProperties, PayloadFormat};
+
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "tracing")]
+pub use self::trace::{extract_trace_context, inject_trace_context};
+
 mod client;
 pub use self::client::Client;
 
+mod cache;
+pub use self::cache::{CacheChange, CacheWatcher, LastValueCache};
+
+mod dedup;
+pub use self::dedup::{Deduplication, DeduplicationBuilder, DeduplicationKey};
+
+mod rate_limit;
+pub use self::rate_limit::{RateLimit, RateLimitBuilder, RateLimitPolicy};
+
 mod config;
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+pub use self::config::TopicLabel;
 pub use self::config::{ClientConfig, ClientConfigBuilder};
 
+#[cfg(feature = "config-file")]
+mod settings;
+#[cfg(feature = "config-file")]
+pub use self::settings::{ReconnectSettings, Settings};
+
+mod credentials;
+#[cfg(feature = "file-credentials")]
+pub use self::credentials::FileCredentialProvider;
+pub use self::credentials::{CredentialProvider, Credentials, CredentialsFuture};
+
+mod tls;
+pub use self::tls::{PemSource, TlsConfig, TlsConfigBuilder};
+
 mod subscription;
-pub use self::subscription::{Subscription, SubscriptionBuilder};
+pub use self::subscription::{RetainHandling, Subscription, SubscriptionBuilder};
+
+mod rpc;
+pub use self::rpc::{Responder, RpcMode};
+
+mod transport;
+#[cfg(feature = "test-util")]
+pub use self::transport::MockTransport;
+#[cfg(feature = "rumqttc")]
+pub use self::transport::RumqttcTransport;
+pub use self::transport::{
+    ConnectOptions, ConnectOptionsBuilder, ConnectResponse, ReasonCode, Transport, TransportEvent,
+    TransportEventHandler, TransportFuture, MQTT_VERSION_3_1_1, MQTT_VERSION_5,
+    MQTT_VERSION_DEFAULT,
+};
+
+mod topic;
+
+#[cfg(feature = "test-util")]
+mod broker;
+#[cfg(feature = "test-util")]
+pub use self::broker::LoopbackBroker;
 
 mod errors;
 pub use self::errors::{Error, Result};
 
-#[cfg(feature = "metrics")]
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
 mod metrics;
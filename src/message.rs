@@ -0,0 +1,424 @@
+#[cfg(feature = "paho")]
+use paho_mqtt::{Properties, PropertyCode};
+use std::{borrow::Cow, fmt, time::Duration};
+
+/// MQTT message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub(crate) topic: String,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) qos: i32,
+    pub(crate) retained: bool,
+    pub(crate) duplicate: bool,
+
+    // Boxed to keep messages small, as they are moved through channels
+    pub(crate) properties: Box<MessageProperties>,
+}
+
+impl Message {
+    /// Create a message.
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>, qos: i32) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos,
+            ..Default::default()
+        }
+    }
+
+    /// Create a message that is retained by the broker.
+    pub fn new_retained(topic: impl Into<String>, payload: impl Into<Vec<u8>>, qos: i32) -> Self {
+        Self {
+            retained: true,
+            ..Self::new(topic, payload, qos)
+        }
+    }
+
+    /// Get the topic of the message.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Get the payload of the message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Get the payload as a string, with invalid UTF-8 sequences replaced.
+    pub fn payload_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    /// Get the QoS of the message.
+    pub fn qos(&self) -> i32 {
+        self.qos
+    }
+
+    /// Get if the message is retained, or was delivered because it was retained.
+    pub fn retained(&self) -> bool {
+        self.retained
+    }
+
+    /// Get if the broker flagged the message as possibly being a redelivery of an earlier
+    /// message.
+    ///
+    /// The flag is not available from the paho backend, where this is always `false`.
+    pub fn duplicate(&self) -> bool {
+        self.duplicate
+    }
+
+    /// Get the MQTT v5 properties of the message.
+    pub fn properties(&self) -> &MessageProperties {
+        &self.properties
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.payload_str())
+    }
+}
+
+/// Format of a message payload, as indicated by the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    /// Payload is unspecified bytes.
+    Unspecified,
+
+    /// Payload is UTF-8 encoded character data.
+    Utf8,
+}
+
+/// Common MQTT v5 message properties.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageProperties {
+    /// MIME type of the payload.
+    pub content_type: Option<String>,
+
+    /// Lifetime of the message, after which the broker will no longer deliver it.
+    pub message_expiry_interval: Option<Duration>,
+
+    /// Application defined key/value pairs.
+    pub user_properties: Vec<(String, String)>,
+
+    /// Topic on which a response to this message is expected.
+    pub response_topic: Option<String>,
+
+    /// Data used to associate a response with its request.
+    pub correlation_data: Option<Vec<u8>>,
+
+    /// Format of the payload.
+    pub payload_format: Option<PayloadFormat>,
+
+    /// Topic alias used in place of the topic name.
+    pub topic_alias: Option<u16>,
+
+    /// Identifiers of the subscriptions that matched a received message.
+    ///
+    /// These are set by the broker and are ignored when sending a message.
+    pub subscription_identifiers: Vec<i32>,
+}
+
+impl MessageProperties {
+    /// Get the value of the first user property with the given key.
+    pub fn user_property(&self, key: &str) -> Option<&str> {
+        self.user_properties
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(feature = "paho")]
+impl From<&Properties> for MessageProperties {
+    fn from(props: &Properties) -> Self {
+        Self {
+            content_type: props.get_string(PropertyCode::ContentType),
+            message_expiry_interval: props
+                .get_int(PropertyCode::MessageExpiryInterval)
+                .map(|secs| Duration::from_secs(secs as u32 as u64)),
+            user_properties: props.user_iter().collect(),
+            response_topic: props.get_string(PropertyCode::ResponseTopic),
+            correlation_data: props.get_binary(PropertyCode::CorrelationData),
+            payload_format: props
+                .get_int(PropertyCode::PayloadFormatIndicator)
+                .map(|v| match v {
+                    1 => PayloadFormat::Utf8,
+                    _ => PayloadFormat::Unspecified,
+                }),
+            topic_alias: props
+                .get_int(PropertyCode::TopicAlias)
+                .map(|alias| alias as u16),
+            subscription_identifiers: props
+                .iter(PropertyCode::SubscriptionIdentifier)
+                .filter_map(|p| p.get_int())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "paho")]
+impl TryFrom<&MessageProperties> for Properties {
+    type Error = crate::Error;
+
+    fn try_from(props: &MessageProperties) -> Result<Self, Self::Error> {
+        let mut p = Properties::new();
+
+        if let Some(content_type) = &props.content_type {
+            p.push_string(PropertyCode::ContentType, content_type)?;
+        }
+
+        if let Some(interval) = props.message_expiry_interval {
+            let secs = u32::try_from(interval.as_secs()).unwrap_or(u32::MAX);
+            p.push_u32(PropertyCode::MessageExpiryInterval, secs)?;
+        }
+
+        for (key, value) in &props.user_properties {
+            p.push_string_pair(PropertyCode::UserProperty, key, value)?;
+        }
+
+        if let Some(topic) = &props.response_topic {
+            p.push_string(PropertyCode::ResponseTopic, topic)?;
+        }
+
+        if let Some(data) = &props.correlation_data {
+            p.push_binary(PropertyCode::CorrelationData, data.clone())?;
+        }
+
+        if let Some(format) = props.payload_format {
+            let indicator = match format {
+                PayloadFormat::Unspecified => 0,
+                PayloadFormat::Utf8 => 1,
+            };
+            p.push_byte(PropertyCode::PayloadFormatIndicator, indicator)?;
+        }
+
+        if let Some(alias) = props.topic_alias {
+            p.push_u16(PropertyCode::TopicAlias, alias)?;
+        }
+
+        Ok(p)
+    }
+}
+
+#[cfg(feature = "paho")]
+impl From<paho_mqtt::Message> for Message {
+    fn from(msg: paho_mqtt::Message) -> Self {
+        Self {
+            topic: msg.topic().to_owned(),
+            payload: msg.payload().to_vec(),
+            qos: msg.qos(),
+            retained: msg.retained(),
+            duplicate: false,
+            properties: Box::new(msg.properties().into()),
+        }
+    }
+}
+
+#[cfg(feature = "paho")]
+impl TryFrom<&Message> for paho_mqtt::Message {
+    type Error = crate::Error;
+
+    fn try_from(msg: &Message) -> Result<Self, Self::Error> {
+        Ok(paho_mqtt::MessageBuilder::new()
+            .topic(msg.topic.as_str())
+            .payload(msg.payload.as_slice())
+            .qos(msg.qos)
+            .retained(msg.retained)
+            .properties(Properties::try_from(&*msg.properties)?)
+            .finalize())
+    }
+}
+
+/// Builder for a [`Message`] with common MQTT v5 properties.
+#[derive(Debug, Clone, Default)]
+pub struct MessageBuilder {
+    topic: String,
+    payload: Vec<u8>,
+    qos: i32,
+    retained: bool,
+    properties: MessageProperties,
+}
+
+impl MessageBuilder {
+    /// Create a builder for a message with the given topic and payload.
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the QoS of the message.
+    pub fn qos(&mut self, qos: i32) -> &mut Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set the QoS of the message to 0 (at most once).
+    pub fn qos_at_most_once(&mut self) -> &mut Self {
+        self.qos(0)
+    }
+
+    /// Set the QoS of the message to 1 (at least once).
+    pub fn qos_at_least_once(&mut self) -> &mut Self {
+        self.qos(1)
+    }
+
+    /// Set the QoS of the message to 2 (exactly once).
+    pub fn qos_exactly_once(&mut self) -> &mut Self {
+        self.qos(2)
+    }
+
+    /// Set if the message should be retained by the broker.
+    pub fn retained(&mut self, retained: bool) -> &mut Self {
+        self.retained = retained;
+        self
+    }
+
+    /// Set the MIME type of the payload.
+    pub fn content_type(&mut self, content_type: impl Into<String>) -> &mut Self {
+        self.properties.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Set the lifetime of the message.
+    ///
+    /// The interval is sent with a resolution of one second.
+    pub fn message_expiry_interval(&mut self, interval: Duration) -> &mut Self {
+        self.properties.message_expiry_interval = Some(interval);
+        self
+    }
+
+    /// Add an application defined key/value pair.
+    pub fn user_property(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.properties
+            .user_properties
+            .push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the topic on which a response to the message is expected.
+    pub fn response_topic(&mut self, topic: impl Into<String>) -> &mut Self {
+        self.properties.response_topic = Some(topic.into());
+        self
+    }
+
+    /// Set the data used to associate a response with the message.
+    pub fn correlation_data(&mut self, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.properties.correlation_data = Some(data.into());
+        self
+    }
+
+    /// Set the format of the payload.
+    pub fn payload_format(&mut self, format: PayloadFormat) -> &mut Self {
+        self.properties.payload_format = Some(format);
+        self
+    }
+
+    /// Set the topic alias used in place of the topic name.
+    pub fn topic_alias(&mut self, alias: u16) -> &mut Self {
+        self.properties.topic_alias = Some(alias);
+        self
+    }
+
+    /// Build the message.
+    pub fn build(&self) -> Message {
+        Message {
+            topic: self.topic.clone(),
+            payload: self.payload.clone(),
+            qos: self.qos,
+            retained: self.retained,
+            duplicate: false,
+            properties: Box::new(self.properties.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        MessageBuilder::new("a/b", "payload")
+            .qos_exactly_once()
+            .retained(true)
+            .content_type("text/plain")
+            .message_expiry_interval(Duration::from_secs(60))
+            .user_property("key", "first")
+            .user_property("key", "second")
+            .response_topic("response/a")
+            .correlation_data("1234")
+            .payload_format(PayloadFormat::Utf8)
+            .topic_alias(3)
+            .build()
+    }
+
+    #[test]
+    fn builder() {
+        let msg = message();
+        assert_eq!(msg.topic(), "a/b");
+        assert_eq!(msg.payload_str(), "payload");
+        assert_eq!(msg.qos(), 2);
+        assert!(msg.retained());
+        assert!(!msg.duplicate());
+
+        let props = msg.properties();
+        assert_eq!(props.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(props.message_expiry_interval, Some(Duration::from_secs(60)));
+        assert_eq!(props.response_topic.as_deref(), Some("response/a"));
+        assert_eq!(props.correlation_data.as_deref(), Some(&b"1234"[..]));
+        assert_eq!(props.payload_format, Some(PayloadFormat::Utf8));
+        assert_eq!(props.topic_alias, Some(3));
+
+        // The first user property with a key is returned
+        assert_eq!(props.user_properties.len(), 2);
+        assert_eq!(props.user_property("key"), Some("first"));
+        assert_eq!(props.user_property("other"), None);
+    }
+
+    #[test]
+    fn no_properties() {
+        let msg = Message::new("a", "", 1);
+        assert_eq!(*msg.properties(), MessageProperties::default());
+        assert!(!msg.retained());
+        assert!(Message::new_retained("a", "", 1).retained());
+    }
+
+    #[cfg(feature = "paho")]
+    #[test]
+    fn paho_conversion() {
+        let msg = message();
+        let converted = paho_mqtt::Message::try_from(&msg).unwrap();
+        assert_eq!(converted.topic(), "a/b");
+        assert_eq!(converted.qos(), 2);
+        assert!(converted.retained());
+
+        assert_eq!(Message::from(converted), msg);
+    }
+
+    #[cfg(feature = "paho")]
+    #[test]
+    fn paho_subscription_identifiers() {
+        let mut props = Properties::new();
+        props
+            .push_int(PropertyCode::SubscriptionIdentifier, 1)
+            .unwrap();
+        props
+            .push_int(PropertyCode::SubscriptionIdentifier, 5)
+            .unwrap();
+
+        assert_eq!(
+            MessageProperties::from(&props).subscription_identifiers,
+            [1, 5]
+        );
+
+        // Subscription identifiers are set by the broker, so are not sent
+        let props = MessageProperties {
+            subscription_identifiers: vec![1],
+            ..Default::default()
+        };
+        assert!(Properties::try_from(&props).unwrap().is_empty());
+    }
+}
@@ -1,33 +1,404 @@
+#[cfg(feature = "opentelemetry")]
+mod opentelemetry;
+#[cfg(feature = "metrics")]
+mod prometheus;
+#[cfg(feature = "metrics-server")]
+mod server;
+
+use crate::{ClientConfig, Message, MessageProperties, Subscription, TopicLabel};
 use derive_builder::Builder;
-use prometheus_client::{
-    encoding::{EncodeLabelSet, EncodeLabelValue},
-    metrics::{counter::Counter, family::Family},
+#[cfg(feature = "metrics")]
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+#[cfg(feature = "metrics-server")]
+use prometheus_client::registry::Registry;
+#[cfg(feature = "metrics-server")]
+use std::net::SocketAddr;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
+#[cfg(feature = "metrics-server")]
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "metrics")]
+pub(crate) use self::prometheus::PrometheusMetrics;
+
+/// A destination for metrics recorded by the client.
+///
+/// Gauges are not recorded through a backend, instead backends read the [`GaugeState`] when
+/// metrics are collected.
+pub(crate) trait MetricsBackend: Send + Sync {
+    /// Count a processed message and its size.
+    fn record_message(&self, labels: &MessageLabels, size: &MessageSize);
+
+    /// Record the time taken for the broker to acknowledge a published message.
+    fn record_publish_latency(&self, labels: &MessageLabels, seconds: f64);
+
+    /// Count a received message that was dropped as a duplicate.
+    fn record_deduplicated(&self, labels: &TopicLabels);
 
-#[derive(Default, Clone)]
+    /// Count a message published over a rate limit.
+    fn record_rate_limited(&self, labels: &RateLimitLabels);
+
+    /// Count a connection change event.
+    fn record_connection_event(&self, labels: &ConnectionEventLabels);
+}
+
+#[derive(Clone)]
 pub(crate) struct MetricCollection {
-    pub(crate) messages: Family<MessageLabels, Counter>,
-    pub(crate) connection_events: Family<ConnectionEventLabels, Counter>,
+    #[cfg(feature = "metrics")]
+    pub(crate) prometheus: PrometheusMetrics,
+    #[cfg(feature = "opentelemetry")]
+    opentelemetry: self::opentelemetry::OpenTelemetryMetrics,
+
+    pub(crate) state: Arc<GaugeState>,
+
+    topic_label: TopicLabel,
+    subscriptions: Arc<Mutex<Vec<Subscription>>>,
+}
+
+impl MetricCollection {
+    pub(crate) fn new(config: &ClientConfig, subscriptions: Arc<Mutex<Vec<Subscription>>>) -> Self {
+        let state = Arc::new(GaugeState::default());
+
+        Self {
+            #[cfg(feature = "metrics")]
+            prometheus: PrometheusMetrics::new(config, state.clone()),
+            #[cfg(feature = "opentelemetry")]
+            opentelemetry: self::opentelemetry::OpenTelemetryMetrics::new(config, state.clone()),
+
+            state,
+
+            topic_label: config.topic_label.clone(),
+            subscriptions,
+        }
+    }
+
+    fn backends(&self) -> Vec<&dyn MetricsBackend> {
+        vec![
+            #[cfg(feature = "metrics")]
+            &self.prometheus,
+            #[cfg(feature = "opentelemetry")]
+            &self.opentelemetry,
+        ]
+    }
+
+    /// Get the value of the topic label for a message topic.
+    fn topic_label(&self, topic: &str) -> String {
+        match &self.topic_label {
+            TopicLabel::Topic => topic.into(),
+            TopicLabel::SubscriptionFilter => self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|s| s.matches(topic))
+                .map(|s| s.topic.clone())
+                .unwrap_or_default(),
+            TopicLabel::Regex { regex, replacement } => match regex.is_match(topic) {
+                true => regex.replace(topic, replacement.as_str()).into_owned(),
+                false => "other".into(),
+            },
+            TopicLabel::PrefixDepth(depth) => {
+                topic.split('/').take(*depth).collect::<Vec<_>>().join("/")
+            }
+            TopicLabel::Drop => String::new(),
+        }
+    }
+
+    /// Count a received message.
+    pub(crate) fn message_received(&self, msg: &Message) {
+        let labels = MessageLabelsBuilder::default()
+            .received()
+            .topic(self.topic_label(msg.topic()))
+            .build()
+            .unwrap();
+        let size = MessageSize::of(msg);
+
+        for backend in self.backends() {
+            backend.record_message(&labels, &size);
+        }
+    }
+
+    /// Count a received message that was dropped as a duplicate.
+    pub(crate) fn message_deduplicated(&self, msg: &Message) {
+        let labels = TopicLabels {
+            topic: self.topic_label(msg.topic()),
+        };
+
+        for backend in self.backends() {
+            backend.record_deduplicated(&labels);
+        }
+    }
+
+    /// Count a message published over a rate limit.
+    pub(crate) fn message_rate_limited(&self, msg: &Message, dropped: bool) {
+        let labels = RateLimitLabels {
+            topic: self.topic_label(msg.topic()),
+            action: if dropped {
+                RateLimitAction::Dropped
+            } else {
+                RateLimitAction::Delayed
+            },
+        };
+
+        for backend in self.backends() {
+            backend.record_rate_limited(&labels);
+        }
+    }
+
+    /// Start recording metrics for a message that is about to be published.
+    pub(crate) fn publish(&self, msg: &Message) -> Publish<'_> {
+        Publish {
+            metrics: self,
+            topic: self.topic_label(msg.topic()),
+            size: MessageSize::of(msg),
+            qos: msg.qos(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Count a connection change event and update the connection state.
+    pub(crate) fn connection_event(&self, labels: ConnectionEventLabels) {
+        let connected = labels.kind == ConnectionEvent::Connected;
+        self.state.connected.store(connected, Ordering::Relaxed);
+        if connected {
+            *self.state.last_connect.lock().unwrap() = Some(Instant::now());
+        }
+
+        for backend in self.backends() {
+            backend.record_connection_event(&labels);
+        }
+    }
+
+    pub(crate) fn set_active_subscriptions(&self, count: usize) {
+        self.state
+            .active_subscriptions
+            .store(count, Ordering::Relaxed);
+    }
+
+    /// A message was queued for the publish loop with [`Client::send`](crate::Client::send).
+    pub(crate) fn message_queued(&self) {
+        self.state.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The publish loop took messages from the queue, or skipped them because it lagged behind, or
+    /// a message could not be queued.
+    ///
+    /// Messages sent directly on the channel are not counted when queued, and skipped events are
+    /// not necessarily messages, so the depth does not go below zero when they are taken.
+    pub(crate) fn messages_dequeued(&self, count: usize) {
+        let _ =
+            self.state
+                .queue_depth
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                    Some(depth.saturating_sub(count))
+                });
+    }
+
+    /// Serve the metrics in `registry` and the health of the client over HTTP.
+    #[cfg(feature = "metrics-server")]
+    pub(crate) fn serve(
+        &self,
+        address: SocketAddr,
+        registry: Registry,
+    ) -> crate::Result<JoinHandle<()>> {
+        self::server::spawn(address, registry, self.state.clone())
+    }
+}
+
+/// Metrics of a message being published.
+pub(crate) struct Publish<'a> {
+    metrics: &'a MetricCollection,
+    topic: String,
+    size: MessageSize,
+    qos: i32,
+    start: Instant,
+}
+
+impl Publish<'_> {
+    fn labels(&self, success: bool) -> MessageLabels {
+        let mut labels = MessageLabelsBuilder::default();
+        labels.sent().topic(self.topic.clone());
+        if !success {
+            labels.failure();
+        }
+        labels.build().unwrap()
+    }
+
+    /// The message was queued for sending.
+    pub(crate) fn queued(&self) {
+        let labels = self.labels(true);
+        for backend in self.metrics.backends() {
+            backend.record_message(&labels, &self.size);
+        }
+    }
+
+    /// The message could not be queued for sending.
+    pub(crate) fn failed(self) {
+        let labels = self.labels(false);
+        for backend in self.metrics.backends() {
+            backend.record_message(&labels, &self.size);
+        }
+    }
+
+    /// Sending the message completed.
+    ///
+    /// The latency is only recorded for QoS 1 and 2, QoS 0 messages complete without being
+    /// acknowledged by the broker.
+    pub(crate) fn delivered(self, success: bool) {
+        if self.qos == 0 {
+            return;
+        }
+
+        let labels = self.labels(success);
+        let seconds = self.start.elapsed().as_secs_f64();
+        for backend in self.metrics.backends() {
+            backend.record_publish_latency(&labels, seconds);
+        }
+    }
+}
+
+/// Current state of the client, read by backends when metrics are collected.
+#[derive(Debug, Default)]
+pub(crate) struct GaugeState {
+    connected: AtomicBool,
+    last_connect: Mutex<Option<Instant>>,
+    active_subscriptions: AtomicUsize,
+    queue_depth: AtomicUsize,
+}
+
+impl GaugeState {
+    pub(crate) fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn seconds_since_connect(&self) -> f64 {
+        self.last_connect
+            .lock()
+            .unwrap()
+            .map_or(0.0, |t| t.elapsed().as_secs_f64())
+    }
+
+    pub(crate) fn active_subscriptions(&self) -> usize {
+        self.active_subscriptions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+}
+
+/// Size of a message in bytes.
+pub(crate) struct MessageSize {
+    pub(crate) payload: u64,
+    pub(crate) wire: u64,
+}
+
+impl MessageSize {
+    pub(crate) fn of(msg: &Message) -> Self {
+        let payload = msg.payload().len();
+
+        // Approximate size of the PUBLISH packet: topic name, packet identifier, properties and
+        // payload, plus the fixed header
+        let mut remaining = 2 + msg.topic().len() + payload;
+        if msg.qos() > 0 {
+            remaining += 2;
+        }
+        let props = properties_len(msg.properties());
+        if props > 0 {
+            remaining += varint_len(props) + props;
+        }
+        let wire = 1 + varint_len(remaining) + remaining;
+
+        Self {
+            payload: payload as u64,
+            wire: wire as u64,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+/// Number of bytes used to encode the properties of a message, excluding their length.
+fn properties_len(props: &MessageProperties) -> usize {
+    // Each property is an identifier byte followed by its value, strings and binary data are
+    // prefixed with their length
+    let string = |s: &String| 1 + 2 + s.len();
+
+    props.content_type.as_ref().map_or(0, string)
+        + props.message_expiry_interval.map_or(0, |_| 1 + 4)
+        + props
+            .user_properties
+            .iter()
+            .map(|(k, v)| 1 + 2 + k.len() + 2 + v.len())
+            .sum::<usize>()
+        + props.response_topic.as_ref().map_or(0, string)
+        + props
+            .correlation_data
+            .as_ref()
+            .map_or(0, |data| 1 + 2 + data.len())
+        + props.payload_format.map_or(0, |_| 1 + 1)
+        + props.topic_alias.map_or(0, |_| 1 + 2)
+        + props
+            .subscription_identifiers
+            .iter()
+            .map(|&id| 1 + varint_len(id as usize))
+            .sum::<usize>()
+}
+
+/// Number of bytes used to encode a value as an MQTT variable byte integer.
+fn varint_len(value: usize) -> usize {
+    match value {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelValue))]
 pub(crate) enum MessageDirection {
     Sent,
     Received,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+impl MessageDirection {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(dead_code))]
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sent => "Sent",
+            Self::Received => "Received",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelValue))]
 pub(crate) enum MessageResult {
     Success,
     Failure,
 }
 
-#[derive(Debug, Builder, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+impl MessageResult {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(dead_code))]
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "Success",
+            Self::Failure => "Failure",
+        }
+    }
+}
+
+#[derive(Debug, Builder, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
 pub(crate) struct MessageLabels {
-    direction: MessageDirection,
-    topic: String,
+    pub(crate) direction: MessageDirection,
+    pub(crate) topic: String,
     #[builder(default = "MessageResult::Success")]
-    result: MessageResult,
+    pub(crate) result: MessageResult,
 }
 
 impl MessageLabelsBuilder {
@@ -44,16 +415,59 @@ impl MessageLabelsBuilder {
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct TopicLabels {
+    pub(crate) topic: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelValue))]
+pub(crate) enum RateLimitAction {
+    Delayed,
+    Dropped,
+}
+
+impl RateLimitAction {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(dead_code))]
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delayed => "Delayed",
+            Self::Dropped => "Dropped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct RateLimitLabels {
+    pub(crate) topic: String,
+    pub(crate) action: RateLimitAction,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelValue))]
 pub(crate) enum ConnectionEvent {
     Connected,
     Disconnected,
     Lost,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+impl ConnectionEvent {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(dead_code))]
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connected => "Connected",
+            Self::Disconnected => "Disconnected",
+            Self::Lost => "Lost",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
 pub(crate) struct ConnectionEventLabels {
-    kind: ConnectionEvent,
+    pub(crate) kind: ConnectionEvent,
 }
 
 impl ConnectionEventLabels {
@@ -75,3 +489,69 @@ impl ConnectionEventLabels {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientConfigBuilder, SubscriptionBuilder};
+
+    fn metrics(topic_label: TopicLabel) -> MetricCollection {
+        let config = ClientConfigBuilder::default()
+            .topic_label(topic_label)
+            .build()
+            .unwrap();
+        let subscriptions = vec![SubscriptionBuilder::default()
+            .topic("sensors/+/temperature".to_owned())
+            .build()
+            .unwrap()];
+
+        MetricCollection::new(&config, Arc::new(Mutex::new(subscriptions)))
+    }
+
+    #[test]
+    fn topic_labels() {
+        let topic = "sensors/kitchen/temperature";
+
+        assert_eq!(metrics(TopicLabel::Topic).topic_label(topic), topic);
+        assert_eq!(metrics(TopicLabel::Drop).topic_label(topic), "");
+        assert_eq!(
+            metrics(TopicLabel::PrefixDepth(2)).topic_label(topic),
+            "sensors/kitchen"
+        );
+
+        let filter = metrics(TopicLabel::SubscriptionFilter);
+        assert_eq!(filter.topic_label(topic), "sensors/+/temperature");
+        assert_eq!(filter.topic_label("other/topic"), "");
+
+        let regex = metrics(TopicLabel::Regex {
+            regex: regex::Regex::new("^sensors/[^/]+/").unwrap(),
+            replacement: "sensors/*/".into(),
+        });
+        assert_eq!(regex.topic_label(topic), "sensors/*/temperature");
+        assert_eq!(regex.topic_label("devices/kitchen/status"), "other");
+    }
+
+    #[test]
+    fn message_size() {
+        let size = MessageSize::of(&Message::new("a/b", "hello", 1));
+        assert_eq!(size.payload, 5);
+        // Fixed header, topic, packet identifier and payload
+        assert_eq!(size.wire, 2 + 5 + 2 + 5);
+
+        let msg = crate::MessageBuilder::new("a/b", "hello")
+            .qos_at_least_once()
+            .content_type("text")
+            .user_property("k", "v")
+            .build();
+        // Properties and their length are included
+        assert_eq!(
+            MessageSize::of(&msg).wire,
+            14 + 1 + (1 + 2 + 4) + (1 + 2 + 1 + 2 + 1)
+        );
+
+        // The remaining length takes two bytes over 127
+        let size = MessageSize::of(&Message::new("a", vec![0; 200], 0));
+        assert_eq!(size.payload, 200);
+        assert_eq!(size.wire, 1 + 2 + 2 + 1 + 200);
+    }
+}
@@ -0,0 +1,443 @@
+use super::{
+    ConnectionEventLabels, GaugeState, MessageLabels, MessageSize, MetricsBackend, RateLimitLabels,
+    TopicLabels,
+};
+use crate::ClientConfig;
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, ObservableGauge, Unit},
+    KeyValue,
+};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub(crate) struct OpenTelemetryMetrics {
+    messages: Counter<u64>,
+    payload_bytes: Counter<u64>,
+    wire_bytes: Counter<u64>,
+    deduplicated_messages: Counter<u64>,
+    rate_limited_messages: Counter<u64>,
+    connection_events: Counter<u64>,
+    publish_latency: Histogram<f64>,
+
+    // Observed by callbacks, held so that the instruments live as long as the client
+    _gauges: Arc<Gauges>,
+}
+
+struct Gauges {
+    _connected: ObservableGauge<u64>,
+    _seconds_since_connect: ObservableGauge<f64>,
+    _active_subscriptions: ObservableGauge<u64>,
+    _queue_depth: ObservableGauge<u64>,
+}
+
+impl OpenTelemetryMetrics {
+    pub(crate) fn new(config: &ClientConfig, state: Arc<GaugeState>) -> Self {
+        let meter = global::meter(env!("CARGO_PKG_NAME"));
+        let name = |n: &str| format!("{}.{}", config.metrics_prefix, n);
+
+        let gauges = Gauges {
+            _connected: {
+                let state = state.clone();
+                meter
+                    .u64_observable_gauge(name("connected"))
+                    .with_description(
+                        "Whether the client is currently connected to the MQTT broker",
+                    )
+                    .with_callback(move |o| o.observe(state.connected().into(), &[]))
+                    .init()
+            },
+            _seconds_since_connect: {
+                let state = state.clone();
+                meter
+                    .f64_observable_gauge(name("seconds_since_connect"))
+                    .with_description("Time since the client last connected to the MQTT broker")
+                    .with_unit(Unit::new("s"))
+                    .with_callback(move |o| o.observe(state.seconds_since_connect(), &[]))
+                    .init()
+            },
+            _active_subscriptions: {
+                let state = state.clone();
+                meter
+                    .u64_observable_gauge(name("active_subscriptions"))
+                    .with_description("Number of MQTT subscriptions made on connect")
+                    .with_callback(move |o| o.observe(state.active_subscriptions() as u64, &[]))
+                    .init()
+            },
+            _queue_depth: meter
+                .u64_observable_gauge(name("queue_depth"))
+                .with_description(
+                    "Number of messages sent with Client::send waiting to be published",
+                )
+                .with_callback(move |o| o.observe(state.queue_depth() as u64, &[]))
+                .init(),
+        };
+
+        Self {
+            messages: meter
+                .u64_counter(name("messages"))
+                .with_description("MQTT messages processed")
+                .init(),
+            payload_bytes: meter
+                .u64_counter(name("payload_bytes"))
+                .with_description("MQTT message payload bytes processed")
+                .with_unit(Unit::new("By"))
+                .init(),
+            wire_bytes: meter
+                .u64_counter(name("wire_bytes"))
+                .with_description("Approximate MQTT PUBLISH packet bytes processed")
+                .with_unit(Unit::new("By"))
+                .init(),
+            deduplicated_messages: meter
+                .u64_counter(name("deduplicated_messages"))
+                .with_description("Received MQTT messages dropped as duplicates")
+                .init(),
+            rate_limited_messages: meter
+                .u64_counter(name("rate_limited_messages"))
+                .with_description("MQTT messages published over a rate limit")
+                .init(),
+            connection_events: meter
+                .u64_counter(name("connection_events"))
+                .with_description("MQTT broker connection change events")
+                .init(),
+            publish_latency: meter
+                .f64_histogram(name("publish_latency"))
+                .with_description(
+                    "Time taken for the broker to acknowledge published MQTT messages",
+                )
+                .with_unit(Unit::new("s"))
+                .init(),
+
+            _gauges: Arc::new(gauges),
+        }
+    }
+}
+
+fn message_attributes(labels: &MessageLabels) -> [KeyValue; 3] {
+    [
+        KeyValue::new("direction", labels.direction.as_str()),
+        KeyValue::new("topic", labels.topic.clone()),
+        KeyValue::new("result", labels.result.as_str()),
+    ]
+}
+
+impl MetricsBackend for OpenTelemetryMetrics {
+    fn record_message(&self, labels: &MessageLabels, size: &MessageSize) {
+        let attributes = message_attributes(labels);
+        self.messages.add(1, &attributes);
+        self.payload_bytes.add(size.payload, &attributes);
+        self.wire_bytes.add(size.wire, &attributes);
+    }
+
+    fn record_publish_latency(&self, labels: &MessageLabels, seconds: f64) {
+        self.publish_latency
+            .record(seconds, &message_attributes(labels));
+    }
+
+    fn record_deduplicated(&self, labels: &TopicLabels) {
+        self.deduplicated_messages
+            .add(1, &[KeyValue::new("topic", labels.topic.clone())]);
+    }
+
+    fn record_rate_limited(&self, labels: &RateLimitLabels) {
+        self.rate_limited_messages.add(
+            1,
+            &[
+                KeyValue::new("topic", labels.topic.clone()),
+                KeyValue::new("action", labels.action.as_str()),
+            ],
+        );
+    }
+
+    fn record_connection_event(&self, labels: &ConnectionEventLabels) {
+        self.connection_events
+            .add(1, &[KeyValue::new("kind", labels.kind.as_str())]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metrics::MetricCollection, ClientConfigBuilder, Message};
+    use opentelemetry::metrics::{
+        AsyncInstrument, Callback, CallbackRegistration, InstrumentProvider, Meter, MeterProvider,
+        MetricsError, Observer, Result, SyncCounter, SyncHistogram,
+    };
+    use std::{
+        any::Any,
+        borrow::Cow,
+        sync::{Mutex, OnceLock},
+    };
+
+    /// Name, value and attributes of a measurement.
+    type Measurement = (String, f64, Vec<(String, String)>);
+
+    /// Meter provider that keeps every measurement, and the callbacks of gauges to observe them.
+    #[derive(Clone, Default)]
+    struct TestProvider {
+        measurements: Arc<Mutex<Vec<Measurement>>>,
+        u64_gauges: Arc<Mutex<Vec<Callback<u64>>>>,
+        f64_gauges: Arc<Mutex<Vec<Callback<f64>>>>,
+    }
+
+    struct TestInstrument {
+        name: String,
+        measurements: Arc<Mutex<Vec<Measurement>>>,
+    }
+
+    impl TestInstrument {
+        fn push(&self, value: f64, attributes: &[KeyValue]) {
+            let attributes = attributes
+                .iter()
+                .map(|kv| (kv.key.to_string(), kv.value.to_string()))
+                .collect();
+            self.measurements
+                .lock()
+                .unwrap()
+                .push((self.name.clone(), value, attributes));
+        }
+    }
+
+    impl SyncCounter<u64> for TestInstrument {
+        fn add(&self, value: u64, attributes: &[KeyValue]) {
+            self.push(value as f64, attributes);
+        }
+    }
+
+    impl SyncHistogram<f64> for TestInstrument {
+        fn record(&self, value: f64, attributes: &[KeyValue]) {
+            self.push(value, attributes);
+        }
+    }
+
+    impl AsyncInstrument<u64> for TestInstrument {
+        fn observe(&self, value: u64, attributes: &[KeyValue]) {
+            self.push(value as f64, attributes);
+        }
+
+        fn as_any(&self) -> Arc<dyn Any> {
+            Arc::new(())
+        }
+    }
+
+    impl AsyncInstrument<f64> for TestInstrument {
+        fn observe(&self, value: f64, attributes: &[KeyValue]) {
+            self.push(value, attributes);
+        }
+
+        fn as_any(&self) -> Arc<dyn Any> {
+            Arc::new(())
+        }
+    }
+
+    impl TestProvider {
+        /// Get the provider, installing it as the global provider the first time.
+        fn global() -> Self {
+            static PROVIDER: OnceLock<TestProvider> = OnceLock::new();
+            PROVIDER
+                .get_or_init(|| {
+                    let provider = TestProvider::default();
+                    global::set_meter_provider(provider.clone());
+                    provider
+                })
+                .clone()
+        }
+
+        fn instrument(&self, name: Cow<'static, str>) -> Arc<TestInstrument> {
+            Arc::new(TestInstrument {
+                name: name.into_owned(),
+                measurements: self.measurements.clone(),
+            })
+        }
+
+        /// Call the callbacks of the gauges.
+        fn observe(&self) {
+            let instrument = self.instrument("".into());
+            for callback in &*self.u64_gauges.lock().unwrap() {
+                callback(&*instrument);
+            }
+            for callback in &*self.f64_gauges.lock().unwrap() {
+                callback(&*instrument);
+            }
+        }
+
+        /// Get the measurements of the instruments whose name starts with a prefix.
+        fn measurements(&self, prefix: &str) -> Vec<Measurement> {
+            self.measurements
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(name, ..)| name.starts_with(prefix))
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl MeterProvider for TestProvider {
+        fn versioned_meter(
+            &self,
+            _name: impl Into<Cow<'static, str>>,
+            _version: Option<impl Into<Cow<'static, str>>>,
+            _schema_url: Option<impl Into<Cow<'static, str>>>,
+            _attributes: Option<Vec<KeyValue>>,
+        ) -> Meter {
+            Meter::new(Arc::new(self.clone()))
+        }
+    }
+
+    impl InstrumentProvider for TestProvider {
+        fn u64_counter(
+            &self,
+            name: Cow<'static, str>,
+            _description: Option<Cow<'static, str>>,
+            _unit: Option<Unit>,
+        ) -> Result<Counter<u64>> {
+            Ok(Counter::new(self.instrument(name)))
+        }
+
+        fn f64_histogram(
+            &self,
+            name: Cow<'static, str>,
+            _description: Option<Cow<'static, str>>,
+            _unit: Option<Unit>,
+        ) -> Result<Histogram<f64>> {
+            Ok(Histogram::new(self.instrument(name)))
+        }
+
+        fn u64_observable_gauge(
+            &self,
+            name: Cow<'static, str>,
+            _description: Option<Cow<'static, str>>,
+            _unit: Option<Unit>,
+            callbacks: Vec<Callback<u64>>,
+        ) -> Result<ObservableGauge<u64>> {
+            let instrument = self.instrument(name);
+            // Observations are attributed to the gauge by the instrument they are made with
+            let named = Arc::clone(&instrument);
+            self.u64_gauges
+                .lock()
+                .unwrap()
+                .extend(callbacks.into_iter().map(|callback| -> Callback<u64> {
+                    let named = named.clone();
+                    Box::new(move |_: &dyn AsyncInstrument<u64>| callback(&*named))
+                }));
+            Ok(ObservableGauge::new(instrument))
+        }
+
+        fn f64_observable_gauge(
+            &self,
+            name: Cow<'static, str>,
+            _description: Option<Cow<'static, str>>,
+            _unit: Option<Unit>,
+            callbacks: Vec<Callback<f64>>,
+        ) -> Result<ObservableGauge<f64>> {
+            let instrument = self.instrument(name);
+            let named = Arc::clone(&instrument);
+            self.f64_gauges
+                .lock()
+                .unwrap()
+                .extend(callbacks.into_iter().map(|callback| -> Callback<f64> {
+                    let named = named.clone();
+                    Box::new(move |_: &dyn AsyncInstrument<f64>| callback(&*named))
+                }));
+            Ok(ObservableGauge::new(instrument))
+        }
+
+        fn register_callback(
+            &self,
+            _instruments: &[Arc<dyn Any>],
+            _callback: Box<dyn Fn(&dyn Observer) + Send + Sync>,
+        ) -> Result<Box<dyn CallbackRegistration>> {
+            Err(MetricsError::Other("Not used by the client".into()))
+        }
+    }
+
+    fn attributes(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn instruments() {
+        let provider = TestProvider::global();
+        let config = ClientConfigBuilder::default()
+            .metrics_prefix("otel_instruments".to_owned())
+            .build()
+            .unwrap();
+        let metrics = MetricCollection::new(&config, Default::default());
+
+        metrics.message_received(&Message::new("a", "hello", 0));
+        metrics.message_deduplicated(&Message::new("a", "hello", 1));
+        metrics.message_rate_limited(&Message::new("b", "", 0), true);
+        metrics.publish(&Message::new("b", "", 1)).delivered(false);
+
+        let received = attributes(&[
+            ("direction", "Received"),
+            ("topic", "a"),
+            ("result", "Success"),
+        ]);
+        let measurements = provider.measurements("otel_instruments.");
+        for expected in [
+            ("otel_instruments.messages", 1.0, received.clone()),
+            ("otel_instruments.payload_bytes", 5.0, received.clone()),
+            ("otel_instruments.wire_bytes", 10.0, received),
+            (
+                "otel_instruments.deduplicated_messages",
+                1.0,
+                attributes(&[("topic", "a")]),
+            ),
+            (
+                "otel_instruments.rate_limited_messages",
+                1.0,
+                attributes(&[("topic", "b"), ("action", "Dropped")]),
+            ),
+        ] {
+            let expected = (expected.0.to_owned(), expected.1, expected.2);
+            assert!(
+                measurements.contains(&expected),
+                "{:?} should be in {:?}",
+                expected,
+                measurements
+            );
+        }
+
+        let latency = measurements
+            .iter()
+            .find(|(name, ..)| name == "otel_instruments.publish_latency")
+            .unwrap();
+        assert_eq!(
+            latency.2,
+            attributes(&[("direction", "Sent"), ("topic", "b"), ("result", "Failure")])
+        );
+    }
+
+    #[test]
+    fn gauges() {
+        let provider = TestProvider::global();
+        let config = ClientConfigBuilder::default()
+            .metrics_prefix("otel_gauges".to_owned())
+            .build()
+            .unwrap();
+        let metrics = MetricCollection::new(&config, Default::default());
+
+        metrics.set_active_subscriptions(3);
+        metrics.message_queued();
+        metrics.message_queued();
+        provider.observe();
+
+        let measurements = provider.measurements("otel_gauges.");
+        let value = |name: &str| {
+            measurements
+                .iter()
+                .rev()
+                .find(|(n, ..)| n == name)
+                .map(|(_, value, _)| *value)
+        };
+        assert_eq!(value("otel_gauges.connected"), Some(0.0));
+        assert_eq!(value("otel_gauges.active_subscriptions"), Some(3.0));
+        assert_eq!(value("otel_gauges.queue_depth"), Some(2.0));
+        assert_eq!(value("otel_gauges.seconds_since_connect"), Some(0.0));
+    }
+}
@@ -0,0 +1,234 @@
+use super::{
+    ConnectionEventLabels, GaugeState, MessageLabels, MessageSize, MetricsBackend, RateLimitLabels,
+    TopicLabels,
+};
+use crate::ClientConfig;
+use prometheus_client::{
+    encoding::{EncodeMetric, MetricEncoder},
+    metrics::{
+        counter::Counter,
+        family::{Family, MetricConstructor},
+        histogram::Histogram,
+        MetricType,
+    },
+    registry::Registry,
+};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub(crate) struct PrometheusMetrics {
+    messages: Family<MessageLabels, Counter>,
+    payload_bytes: Family<MessageLabels, Counter>,
+    wire_bytes: Family<MessageLabels, Counter>,
+    deduplicated_messages: Family<TopicLabels, Counter>,
+    rate_limited_messages: Family<RateLimitLabels, Counter>,
+    connection_events: Family<ConnectionEventLabels, Counter>,
+    publish_latency: Family<MessageLabels, Histogram, HistogramConstructor>,
+
+    state: Arc<GaugeState>,
+}
+
+impl PrometheusMetrics {
+    pub(crate) fn new(config: &ClientConfig, state: Arc<GaugeState>) -> Self {
+        Self {
+            messages: Default::default(),
+            payload_bytes: Default::default(),
+            wire_bytes: Default::default(),
+            deduplicated_messages: Default::default(),
+            rate_limited_messages: Default::default(),
+            connection_events: Default::default(),
+            publish_latency: Family::new_with_constructor(HistogramConstructor {
+                buckets: config.publish_latency_buckets.clone(),
+            }),
+
+            state,
+        }
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register("messages", "MQTT messages processed", self.messages.clone());
+
+        registry.register(
+            "payload_bytes",
+            "MQTT message payload bytes processed",
+            self.payload_bytes.clone(),
+        );
+
+        registry.register(
+            "wire_bytes",
+            "Approximate MQTT PUBLISH packet bytes processed",
+            self.wire_bytes.clone(),
+        );
+
+        registry.register(
+            "deduplicated_messages",
+            "Received MQTT messages dropped as duplicates",
+            self.deduplicated_messages.clone(),
+        );
+
+        registry.register(
+            "rate_limited_messages",
+            "MQTT messages published over a rate limit",
+            self.rate_limited_messages.clone(),
+        );
+
+        registry.register(
+            "connection_events",
+            "MQTT broker connection change events",
+            self.connection_events.clone(),
+        );
+
+        registry.register(
+            "publish_latency_seconds",
+            "Time taken for the broker to acknowledge published MQTT messages",
+            self.publish_latency.clone(),
+        );
+
+        registry.register(
+            "connected",
+            "Whether the client is currently connected to the MQTT broker",
+            StateGauge::new(&self.state, |s| s.connected().into()),
+        );
+
+        registry.register(
+            "seconds_since_connect",
+            "Time since the client last connected to the MQTT broker",
+            StateGauge::new(&self.state, GaugeState::seconds_since_connect),
+        );
+
+        registry.register(
+            "active_subscriptions",
+            "Number of MQTT subscriptions made on connect",
+            StateGauge::new(&self.state, |s| s.active_subscriptions() as f64),
+        );
+
+        registry.register(
+            "queue_depth",
+            "Number of messages sent with Client::send waiting to be published",
+            StateGauge::new(&self.state, |s| s.queue_depth() as f64),
+        );
+    }
+}
+
+impl MetricsBackend for PrometheusMetrics {
+    fn record_message(&self, labels: &MessageLabels, size: &MessageSize) {
+        self.messages.get_or_create(labels).inc();
+        self.payload_bytes
+            .get_or_create(labels)
+            .inc_by(size.payload);
+        self.wire_bytes.get_or_create(labels).inc_by(size.wire);
+    }
+
+    fn record_publish_latency(&self, labels: &MessageLabels, seconds: f64) {
+        self.publish_latency.get_or_create(labels).observe(seconds);
+    }
+
+    fn record_deduplicated(&self, labels: &TopicLabels) {
+        self.deduplicated_messages.get_or_create(labels).inc();
+    }
+
+    fn record_rate_limited(&self, labels: &RateLimitLabels) {
+        self.rate_limited_messages.get_or_create(labels).inc();
+    }
+
+    fn record_connection_event(&self, labels: &ConnectionEventLabels) {
+        self.connection_events.get_or_create(labels).inc();
+    }
+}
+
+/// Gauge of a value of the client state, evaluated when metrics are encoded.
+#[derive(Debug)]
+struct StateGauge {
+    state: Arc<GaugeState>,
+    value: fn(&GaugeState) -> f64,
+}
+
+impl StateGauge {
+    fn new(state: &Arc<GaugeState>, value: fn(&GaugeState) -> f64) -> Self {
+        Self {
+            state: state.clone(),
+            value,
+        }
+    }
+}
+
+impl EncodeMetric for StateGauge {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        encoder.encode_gauge(&(self.value)(&self.state))
+    }
+
+    fn metric_type(&self) -> MetricType {
+        MetricType::Gauge
+    }
+}
+
+#[derive(Clone)]
+struct HistogramConstructor {
+    buckets: Vec<f64>,
+}
+
+impl MetricConstructor<Histogram> for HistogramConstructor {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.buckets.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metrics::MetricCollection, ClientConfigBuilder, Message};
+
+    /// Encode the metrics of a collection in the text format.
+    fn encode(metrics: &MetricCollection) -> String {
+        let mut registry = Registry::default();
+        metrics.prometheus.register(&mut registry);
+
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        text
+    }
+
+    #[test]
+    fn publish_latency() {
+        let config = ClientConfigBuilder::default()
+            .publish_latency_buckets(vec![0.5, 1.0])
+            .build()
+            .unwrap();
+        let metrics = MetricCollection::new(&config, Default::default());
+
+        metrics.publish(&Message::new("a", "1", 1)).delivered(true);
+        metrics.publish(&Message::new("a", "1", 2)).delivered(false);
+        metrics.publish(&Message::new("a", "1", 0)).delivered(true);
+
+        // QoS 0 messages are not acknowledged, so have no latency
+        let text = encode(&metrics);
+        for line in [
+            r#"publish_latency_seconds_count{direction="Sent",topic="a",result="Success"} 1"#,
+            r#"publish_latency_seconds_count{direction="Sent",topic="a",result="Failure"} 1"#,
+            r#"publish_latency_seconds_bucket{le="0.5",direction="Sent",topic="a",result="Success"} 1"#,
+            r#"publish_latency_seconds_bucket{le="1.0",direction="Sent",topic="a",result="Success"} 1"#,
+        ] {
+            assert!(text.contains(line), "{} should be in:\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        let metrics = MetricCollection::new(&Default::default(), Default::default());
+
+        metrics.message_received(&Message::new("a", "hello", 0));
+        metrics.message_received(&Message::new("a", "hi", 0));
+        metrics.publish(&Message::new("b", "hello", 1)).queued();
+
+        let text = encode(&metrics);
+        for line in [
+            r#"messages_total{direction="Received",topic="a",result="Success"} 2"#,
+            r#"payload_bytes_total{direction="Received",topic="a",result="Success"} 7"#,
+            r#"wire_bytes_total{direction="Received",topic="a",result="Success"} 17"#,
+            r#"payload_bytes_total{direction="Sent",topic="b",result="Success"} 5"#,
+            r#"wire_bytes_total{direction="Sent",topic="b",result="Success"} 12"#,
+        ] {
+            assert!(text.contains(line), "{} should be in:\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use super::GaugeState;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus_client::{encoding::text::encode, registry::Registry};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::task::JoinHandle;
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Bind an HTTP server to `address` and serve `/metrics` and `/healthz` from a background task.
+///
+/// `/metrics` serves the contents of `registry` in the OpenMetrics text format, `/healthz`
+/// responds with `200 OK` while the client is connected and `503 Service Unavailable` otherwise.
+pub(crate) fn spawn(
+    address: SocketAddr,
+    registry: Registry,
+    state: Arc<GaugeState>,
+) -> crate::Result<JoinHandle<()>> {
+    let registry = Arc::new(registry);
+
+    let make_service = make_service_fn(move |_| {
+        let registry = registry.clone();
+        let state = state.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handle(&registry, &state, &req);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&address)?.serve(make_service);
+    tracing::info!(address = %server.local_addr(), "Serving metrics");
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!(error = %e, "Metrics server error");
+        }
+    }))
+}
+
+fn handle(registry: &Registry, state: &GaugeState, req: &Request<Body>) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut body = String::new();
+            match encode(&mut body, registry) {
+                Ok(()) => Response::builder()
+                    .header(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)
+                    .body(body.into())
+                    .unwrap(),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to encode metrics");
+                    status(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        }
+        (&Method::GET, "/healthz") => {
+            if state.connected() {
+                status(StatusCode::OK)
+            } else {
+                status(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        }
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Create an empty response with a status code, using the reason phrase as the body.
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(code.canonical_reason().unwrap_or_default().into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::metrics::counter::Counter;
+    use std::sync::atomic::Ordering;
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let mut registry = Registry::default();
+        let counter = Counter::<u64>::default();
+        counter.inc();
+        registry.register("requests", "Requests", counter);
+
+        let response = handle(
+            &registry,
+            &Default::default(),
+            &request(Method::GET, "/metrics"),
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], OPENMETRICS_CONTENT_TYPE);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("requests_total 1"));
+        assert!(body.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn health() {
+        let registry = Registry::default();
+        let state = GaugeState::default();
+
+        let response = handle(&registry, &state, &request(Method::GET, "/healthz"));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.connected.store(true, Ordering::Relaxed);
+        let response = handle(&registry, &state, &request(Method::GET, "/healthz"));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn not_found() {
+        let registry = Registry::default();
+        let state = GaugeState::default();
+
+        for (method, path) in [(Method::GET, "/"), (Method::POST, "/metrics")] {
+            let response = handle(&registry, &state, &request(method, path));
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn address_in_use() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let result = spawn(address, Registry::default(), Default::default());
+        assert!(matches!(result, Err(crate::Error::MetricsServerError(_))));
+    }
+}
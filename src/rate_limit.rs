@@ -0,0 +1,426 @@
+use crate::topic;
+use derive_builder::Builder;
+use std::time::{Duration, Instant};
+
+/// Limit of the rate at which messages are published, using a token bucket.
+///
+/// A message is published only when every limit whose topic filter matches its topic allows it.
+///
+/// The rate, burst and topic filter of a limit are validated when it is built, or deserialized
+/// with the `config-file` feature.
+#[derive(Builder, Debug, Clone)]
+#[builder(build_fn(validate = "Self::validate"))]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(try_from = "RateLimitConfig")
+)]
+pub struct RateLimit {
+    /// Topic filter of the messages that are limited, all messages are limited if not set.
+    ///
+    /// Messages on all the topics that match the filter share the limit.
+    #[builder(default, setter(into, strip_option))]
+    pub(crate) topic: Option<String>,
+
+    /// Messages per second.
+    pub(crate) rate: f64,
+
+    /// Messages that can be published at once after not publishing for a while.
+    #[builder(default = "1")]
+    pub(crate) burst: u32,
+
+    /// What happens to messages over the limit.
+    #[builder(default)]
+    pub(crate) policy: RateLimitPolicy,
+}
+
+impl RateLimitBuilder {
+    fn validate(&self) -> Result<(), String> {
+        match self.rate {
+            Some(rate) => validate(
+                self.topic.as_ref().and_then(Option::as_deref),
+                rate,
+                self.burst.unwrap_or(1),
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check that a limit has a valid topic filter, a positive finite rate and a burst of at least 1.
+fn validate(topic: Option<&str>, rate: f64, burst: u32) -> Result<(), String> {
+    if let Some(filter) = topic {
+        if !topic::is_valid_filter(filter) {
+            return Err(format!("invalid topic filter \"{}\"", filter));
+        }
+    }
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(format!("invalid rate {}", rate));
+    }
+    if burst == 0 {
+        return Err("burst must be at least 1".into());
+    }
+    Ok(())
+}
+
+/// What happens to messages published over a [`RateLimit`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum RateLimitPolicy {
+    /// Wait until the message is allowed, which delays all the messages after it.
+    ///
+    /// The delayed messages are queued without a bound, they are not limited by the size of the
+    /// client channel.
+    #[default]
+    Delay,
+
+    /// Drop the message.
+    Drop,
+}
+
+/// Representation of a [`RateLimit`] in a configuration file.
+#[cfg(feature = "config-file")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    topic: Option<String>,
+    rate: f64,
+    #[serde(default = "default_burst")]
+    burst: u32,
+    #[serde(default)]
+    policy: RateLimitPolicy,
+}
+
+#[cfg(feature = "config-file")]
+fn default_burst() -> u32 {
+    1
+}
+
+#[cfg(feature = "config-file")]
+impl TryFrom<RateLimitConfig> for RateLimit {
+    type Error = String;
+
+    fn try_from(config: RateLimitConfig) -> Result<Self, Self::Error> {
+        validate(config.topic.as_deref(), config.rate, config.burst)?;
+
+        Ok(Self {
+            topic: config.topic,
+            rate: config.rate,
+            burst: config.burst,
+            policy: config.policy,
+        })
+    }
+}
+
+/// Outcome of checking a message against the rate limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Allowed,
+    Delayed,
+    Dropped,
+}
+
+/// Rate limits of the messages published by a client.
+pub(crate) struct RateLimiter {
+    buckets: Vec<Bucket>,
+}
+
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+
+    /// If the last message checked against this limit was over it.
+    limited: bool,
+}
+
+impl Bucket {
+    fn matches(&self, topic: &str) -> bool {
+        match &self.limit.topic {
+            Some(filter) => topic::matches(filter, topic),
+            None => true,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Time until a token is available.
+    fn wait(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64((1.0 - self.tokens) / self.limit.rate)
+                .unwrap_or(Duration::MAX)
+        }
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: &[RateLimit]) -> Self {
+        let now = Instant::now();
+
+        Self {
+            buckets: limits
+                .iter()
+                .map(|limit| Bucket {
+                    limit: limit.clone(),
+                    tokens: limit.burst as f64,
+                    updated: now,
+                    limited: false,
+                })
+                .collect(),
+        }
+    }
+
+    /// Check a message on `topic` against the limits, waiting if it is delayed.
+    ///
+    /// `limited` is called with each limit the message is over, when the previous message checked
+    /// against the limit was not.
+    pub(crate) async fn admit(
+        &mut self,
+        topic: &str,
+        mut limited: impl FnMut(&RateLimit),
+    ) -> Admission {
+        let now = Instant::now();
+        let mut buckets: Vec<&mut Bucket> = self
+            .buckets
+            .iter_mut()
+            .filter(|b| b.matches(topic))
+            .collect();
+        for bucket in buckets.iter_mut() {
+            bucket.refill(now);
+        }
+
+        // Messages over a limit that drops are dropped without using tokens of the other limits
+        let mut dropped = false;
+        for bucket in buckets.iter_mut() {
+            if bucket.limit.policy == RateLimitPolicy::Drop && bucket.tokens < 1.0 {
+                if !bucket.limited {
+                    limited(&bucket.limit);
+                }
+                bucket.limited = true;
+                dropped = true;
+            }
+        }
+        if dropped {
+            return Admission::Dropped;
+        }
+
+        let mut wait = Duration::ZERO;
+        for bucket in buckets.iter_mut() {
+            let w = bucket.wait();
+            if w.is_zero() {
+                bucket.limited = false;
+            } else {
+                if !bucket.limited {
+                    limited(&bucket.limit);
+                }
+                bucket.limited = true;
+                wait = wait.max(w);
+            }
+        }
+
+        let admission = if wait.is_zero() {
+            Admission::Allowed
+        } else {
+            tokio::time::sleep(wait).await;
+            let now = Instant::now();
+            for bucket in buckets.iter_mut() {
+                bucket.refill(now);
+            }
+            Admission::Delayed
+        };
+
+        for bucket in buckets {
+            bucket.tokens -= 1.0;
+        }
+
+        admission
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(topic: Option<&str>, rate: f64, burst: u32, policy: RateLimitPolicy) -> RateLimit {
+        let mut builder = RateLimitBuilder::default();
+        builder.rate(rate).burst(burst).policy(policy);
+        if let Some(topic) = topic {
+            builder.topic(topic);
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn validation() {
+        assert!(RateLimitBuilder::default().rate(1.0).build().is_ok());
+        assert!(RateLimitBuilder::default().rate(0.0).build().is_err());
+        assert!(RateLimitBuilder::default().rate(-1.0).build().is_err());
+        assert!(RateLimitBuilder::default().rate(f64::NAN).build().is_err());
+        assert!(RateLimitBuilder::default()
+            .rate(f64::INFINITY)
+            .build()
+            .is_err());
+        assert!(RateLimitBuilder::default()
+            .rate(1.0)
+            .burst(0u32)
+            .build()
+            .is_err());
+        assert!(RateLimitBuilder::default()
+            .rate(1.0)
+            .topic("a/#/b")
+            .build()
+            .is_err());
+        assert!(RateLimitBuilder::default().build().is_err());
+    }
+
+    #[tokio::test]
+    async fn burst() {
+        let mut limiter = RateLimiter::new(&[limit(None, 1.0, 3, RateLimitPolicy::Drop)]);
+
+        let mut limited = 0;
+        let mut admissions = Vec::new();
+        for _ in 0..5 {
+            admissions.push(limiter.admit("a", |_| limited += 1).await);
+        }
+
+        assert_eq!(
+            admissions,
+            [
+                Admission::Allowed,
+                Admission::Allowed,
+                Admission::Allowed,
+                Admission::Dropped,
+                Admission::Dropped
+            ]
+        );
+        // Reported once until a message is allowed again
+        assert_eq!(limited, 1);
+    }
+
+    #[tokio::test]
+    async fn refill() {
+        let mut limiter = RateLimiter::new(&[limit(None, 20.0, 1, RateLimitPolicy::Drop)]);
+
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Allowed);
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Dropped);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Allowed);
+    }
+
+    #[tokio::test]
+    async fn delay() {
+        let mut limiter = RateLimiter::new(&[limit(None, 20.0, 1, RateLimitPolicy::Delay)]);
+
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Allowed);
+
+        let start = Instant::now();
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Delayed);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn topics() {
+        let mut limiter = RateLimiter::new(&[
+            limit(Some("a/#"), 1.0, 1, RateLimitPolicy::Drop),
+            limit(Some("b"), 1.0, 1, RateLimitPolicy::Drop),
+        ]);
+
+        // Topics matching the same filter share a limit
+        assert_eq!(limiter.admit("a/1", |_| {}).await, Admission::Allowed);
+        assert_eq!(limiter.admit("a/2", |_| {}).await, Admission::Dropped);
+
+        assert_eq!(limiter.admit("b", |_| {}).await, Admission::Allowed);
+        assert_eq!(limiter.admit("c", |_| {}).await, Admission::Allowed);
+        assert_eq!(limiter.admit("c", |_| {}).await, Admission::Allowed);
+    }
+
+    #[tokio::test]
+    async fn dropped_uses_no_tokens() {
+        let mut limiter = RateLimiter::new(&[
+            limit(None, 1.0, 2, RateLimitPolicy::Delay),
+            limit(Some("a"), 1.0, 1, RateLimitPolicy::Drop),
+        ]);
+
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Allowed);
+        assert_eq!(limiter.admit("a", |_| {}).await, Admission::Dropped);
+
+        // The dropped message did not use a token of the limit of all messages
+        assert_eq!(limiter.admit("b", |_| {}).await, Admission::Allowed);
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn client() {
+        use crate::{transport::start_client, ClientConfigBuilder, Event, Message, StatusEvent};
+
+        let config = ClientConfigBuilder::default()
+            .rate_limits(vec![limit(None, 1.0, 2, RateLimitPolicy::Drop)])
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+        let mut rx = client.rx_channel();
+
+        for i in 0..4 {
+            client
+                .send(Message::new(format!("a/{}", i), "", 0))
+                .unwrap();
+        }
+
+        // The rate limited event is raised when the third message is dropped, after the first
+        // two were published
+        loop {
+            if let Event::Status(StatusEvent::RateLimited { policy, .. }) = rx.recv().await.unwrap()
+            {
+                assert_eq!(policy, RateLimitPolicy::Drop);
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let published = transport.published();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].topic(), "a/0");
+        assert_eq!(published[1].topic(), "a/1");
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn client_delay() {
+        use crate::{transport::start_client, ClientConfigBuilder, Message};
+
+        let config = ClientConfigBuilder::default()
+            .channel_size(4usize)
+            .rate_limits(vec![limit(None, 1000.0, 1, RateLimitPolicy::Delay)])
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+
+        // More messages than fit in the channel are sent while publishing is delayed
+        for i in 0..20 {
+            client
+                .send(Message::new(format!("a/{}", i), "", 0))
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        let published =
+            tokio::time::timeout(Duration::from_secs(5), transport.wait_for_published(20))
+                .await
+                .expect("all the messages should be published");
+        assert!(published
+            .iter()
+            .enumerate()
+            .all(|(i, msg)| msg.topic() == format!("a/{}", i)));
+    }
+}
@@ -0,0 +1,472 @@
+use crate::{topic, Client, Event, Message, MessageBuilder, Subscription};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, oneshot},
+    task::JoinHandle,
+};
+use tracing::Instrument;
+
+/// How requests and their responses are associated with each other.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum RpcMode {
+    /// Requests carry the MQTT v5 response topic and correlation data properties.
+    #[default]
+    Properties,
+
+    /// The request ID and response topic are embedded in the topic path, for use with brokers
+    /// that do not support MQTT v5.
+    ///
+    /// Requests for a service are published to `<prefix>/<service>/<id>/request` and responses
+    /// are published to `<prefix>/<service>/<id>/response`.
+    TopicConvention { prefix: String },
+}
+
+impl RpcMode {
+    /// Extract the ID of the request a message is responding to, if any.
+    pub(crate) fn response_id(&self, response_topic: &str, msg: &Message) -> Option<Vec<u8>> {
+        match self {
+            Self::Properties if msg.topic() == response_topic => {
+                msg.properties().correlation_data.clone()
+            }
+            Self::Properties => None,
+            Self::TopicConvention { prefix } => {
+                // The prefix and service name may span several levels, the ID is the level
+                // before "response"
+                let (service, id) = msg
+                    .topic()
+                    .strip_prefix(prefix.as_str())?
+                    .strip_prefix('/')?
+                    .strip_suffix("/response")?
+                    .rsplit_once('/')?;
+
+                (!service.is_empty() && !id.is_empty()).then(|| id.as_bytes().to_vec())
+            }
+        }
+    }
+}
+
+/// Table of requests that are awaiting a response.
+#[derive(Clone)]
+pub(crate) struct PendingRequests {
+    id_prefix: String,
+    next_id: Arc<AtomicU64>,
+    requests: Arc<Mutex<HashMap<Vec<u8>, oneshot::Sender<Message>>>>,
+}
+
+impl PendingRequests {
+    /// Create a table of requests with IDs unique to the given client ID.
+    pub(crate) fn new(client_id: &str) -> Self {
+        // IDs may be used as a topic level, so must not contain separators or wildcards
+        let id_prefix = client_id.replace(['/', '+', '#'], "_");
+
+        Self {
+            id_prefix,
+            next_id: Default::default(),
+            requests: Default::default(),
+        }
+    }
+
+    /// Register a new request, returning its unique ID and a channel on which the response will
+    /// be delivered.
+    pub(crate) fn register(&self) -> (String, oneshot::Receiver<Message>) {
+        let id = format!(
+            "{}-{}",
+            self.id_prefix,
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(id.clone().into_bytes(), tx);
+
+        (id, rx)
+    }
+
+    /// Remove a request that is no longer awaiting a response.
+    pub(crate) fn cancel(&self, id: &str) {
+        self.requests.lock().unwrap().remove(id.as_bytes());
+    }
+
+    /// Deliver a response to the request with the given ID.
+    ///
+    /// If no such request is pending then the message is returned.
+    pub(crate) fn resolve(&self, id: &[u8], msg: Message) -> Result<(), Message> {
+        match self.requests.lock().unwrap().remove(id) {
+            Some(tx) => {
+                if let Err(msg) = tx.send(msg) {
+                    tracing::debug!(
+                        topic = msg.topic(),
+                        "Response received for abandoned request"
+                    );
+                }
+                Ok(())
+            }
+            None => Err(msg),
+        }
+    }
+}
+
+/// Answers requests received on a topic filter.
+///
+/// When using [`RpcMode::Properties`] requests are expected to carry the MQTT v5 response topic
+/// and correlation data properties, the reply is published to the response topic with the
+/// correlation data of the request.
+///
+/// When using [`RpcMode::TopicConvention`] the topic of the subscription is the name of the
+/// service, the reply is published to the response topic corresponding to the request topic.
+///
+/// The topic filter is unsubscribed from when the responder is stopped or dropped.
+pub struct Responder {
+    client: Client,
+    broker_topic: String,
+    handle: JoinHandle<()>,
+}
+
+impl Responder {
+    /// Subscribe to requests using the supplied subscription and answer them using `handler`.
+    ///
+    /// `handler` is given each request and returns the reply payload, or `None` if no reply
+    /// should be sent.
+    pub fn new<F>(client: &Client, subscription: Subscription, handler: F) -> Self
+    where
+        F: Fn(&Message) -> Option<Vec<u8>> + Send + 'static,
+    {
+        let mode = client.config().rpc_mode.clone();
+
+        let subscription = match &mode {
+            RpcMode::Properties => subscription,
+            RpcMode::TopicConvention { prefix } => Subscription {
+                topic: format!("{}/{}/+/request", prefix, subscription.topic),
+                ..subscription
+            },
+        };
+
+        let filter = subscription.topic.clone();
+        let broker_topic = subscription.broker_topic();
+        let mut rx_channel = client.rx_channel();
+        let span = tracing::info_span!(parent: client.span(), "mqtt_responder", filter = %filter);
+
+        client.subscribe(subscription);
+
+        // Replies are sent with the client so that they carry the trace context
+        let responder_client = client.clone();
+        let client = client.clone();
+        let handle = tokio::spawn(
+            async move {
+                loop {
+                    match rx_channel.recv().await {
+                        Ok(Event::Rx(msg)) if topic::matches(&filter, msg.topic()) => {
+                            #[cfg(feature = "tracing")]
+                            let _span = crate::trace::receive_span(&msg).entered();
+
+                            if let Some(payload) = handler(&msg) {
+                                let response = match response_for(&mode, &msg, payload) {
+                                    Ok(response) => response,
+                                    Err(e) => {
+                                        tracing::warn!(
+                                            topic = msg.topic(),
+                                            error = %e,
+                                            "Cannot respond to request"
+                                        );
+                                        continue;
+                                    }
+                                };
+
+                                if let Err(e) = client.send(response) {
+                                    tracing::error!(error = %e, "Failed to send response");
+                                }
+                            }
+                        }
+                        Ok(Event::Stop) | Err(RecvError::Closed) => {
+                            tracing::debug!("Responder stopped");
+                            return;
+                        }
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Receive error");
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            .instrument(span),
+        );
+
+        Self {
+            client: responder_client,
+            broker_topic,
+            handle,
+        }
+    }
+
+    /// Stop answering requests and unsubscribe from the topic filter.
+    pub fn stop(self) {}
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.handle.abort();
+        self.client.unsubscribe(&self.broker_topic);
+    }
+}
+
+/// Create the response to a request.
+fn response_for(mode: &RpcMode, request: &Message, payload: Vec<u8>) -> crate::Result<Message> {
+    let no_response_topic = || crate::Error::NoResponseTopic(request.topic().into());
+
+    match mode {
+        RpcMode::Properties => {
+            let props = request.properties();
+            let topic = props.response_topic.clone().ok_or_else(no_response_topic)?;
+
+            let mut response = MessageBuilder::new(topic, payload);
+            response.qos(request.qos());
+            if let Some(correlation_data) = &props.correlation_data {
+                response.correlation_data(correlation_data.clone());
+            }
+            Ok(response.build())
+        }
+        RpcMode::TopicConvention { .. } => {
+            let topic = request
+                .topic()
+                .strip_suffix("/request")
+                .map(|base| format!("{}/response", base))
+                .ok_or_else(no_response_topic)?;
+
+            Ok(Message::new(topic, payload, request.qos()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve() {
+        let pending = PendingRequests::new("client");
+        let (id, rx) = pending.register();
+
+        assert!(pending
+            .resolve(id.as_bytes(), Message::new("a", "1", 1))
+            .is_ok());
+        assert_eq!(rx.await.unwrap().payload(), b"1");
+
+        // A request is resolved only once
+        let msg = pending
+            .resolve(id.as_bytes(), Message::new("a", "2", 1))
+            .unwrap_err();
+        assert_eq!(msg.payload(), b"2");
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let pending = PendingRequests::new("client");
+        let (id, rx) = pending.register();
+
+        pending.cancel(&id);
+        assert!(rx.await.is_err());
+        assert!(pending
+            .resolve(id.as_bytes(), Message::new("a", "1", 1))
+            .is_err());
+    }
+
+    #[test]
+    fn abandoned() {
+        let pending = PendingRequests::new("client");
+        let (id, rx) = pending.register();
+
+        drop(rx);
+        assert!(pending
+            .resolve(id.as_bytes(), Message::new("a", "1", 1))
+            .is_ok());
+    }
+
+    #[test]
+    fn ids() {
+        let pending = PendingRequests::new("site/+/#");
+        let (first, _) = pending.register();
+        let (second, _) = pending.register();
+
+        assert_ne!(first, second);
+        assert!(first.starts_with("site____-"));
+        assert!(!first.contains(['/', '+', '#']));
+
+        // Clones share the table
+        let (id, _) = pending.clone().register();
+        assert!(pending
+            .resolve(id.as_bytes(), Message::new("a", "", 1))
+            .is_ok());
+    }
+
+    #[test]
+    fn properties_response_id() {
+        let msg = MessageBuilder::new("response/client", "")
+            .correlation_data(b"1".to_vec())
+            .build();
+        assert_eq!(
+            RpcMode::Properties.response_id("response/client", &msg),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(
+            RpcMode::Properties.response_id("response/other", &msg),
+            None
+        );
+        assert_eq!(
+            RpcMode::Properties
+                .response_id("response/client", &Message::new("response/client", "", 1)),
+            None
+        );
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn properties_request() {
+        use crate::transport::start_client;
+        use std::time::Duration;
+
+        let (client, transport) = start_client(Default::default()).await;
+        let mut rx = client.rx_channel();
+
+        let request = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .request("service", "request", Duration::from_secs(5))
+                    .await
+            })
+        };
+        let published = transport.wait_for_published(1).await;
+        assert_eq!(published[0].topic(), "service");
+        let properties = published[0].properties();
+        assert_eq!(
+            properties.response_topic.as_deref(),
+            Some("response/client")
+        );
+        assert!(transport
+            .subscriptions()
+            .iter()
+            .any(|s| s.topic() == "response/client"));
+
+        // The response is delivered to the requester only
+        transport.inject_message(
+            MessageBuilder::new("response/client", "response")
+                .correlation_data(properties.correlation_data.clone().unwrap())
+                .build(),
+        );
+        assert_eq!(request.await.unwrap().unwrap().payload(), b"response");
+        while let Ok(event) = rx.try_recv() {
+            assert!(!matches!(event, Event::Rx(_)));
+        }
+
+        // Requests without a response time out
+        let result = client
+            .request("service", "request", Duration::from_millis(10))
+            .await;
+        assert!(matches!(result, Err(crate::Error::RequestTimeout)));
+    }
+
+    #[test]
+    fn topic_convention_response_id() {
+        let mode = RpcMode::TopicConvention {
+            prefix: "rpc".into(),
+        };
+        let id = |topic: &str| mode.response_id("", &Message::new(topic, "", 1));
+        assert_eq!(id("rpc/a/1/response"), Some(b"1".to_vec()));
+        assert_eq!(id("rpc/a/b/1/response"), Some(b"1".to_vec()));
+        assert_eq!(id("rpc/a/1/request"), None);
+        assert_eq!(id("rpc/1/response"), None);
+        assert_eq!(id("other/a/1/response"), None);
+
+        let mode = RpcMode::TopicConvention {
+            prefix: "site/rpc".into(),
+        };
+        assert_eq!(
+            mode.response_id("", &Message::new("site/rpc/a/1/response", "", 1)),
+            Some(b"1".to_vec())
+        );
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn topic_convention_request() {
+        use crate::{transport::start_client, ClientConfigBuilder};
+        use std::time::Duration;
+
+        let config = ClientConfigBuilder::default()
+            .rpc_mode(RpcMode::TopicConvention {
+                prefix: "rpc".into(),
+            })
+            .build()
+            .unwrap();
+        let (client, transport) = start_client(config).await;
+        let mut rx = client.rx_channel();
+
+        let request = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .request("service", "request", Duration::from_secs(5))
+                    .await
+            })
+        };
+        let published = transport.wait_for_published(1).await;
+        let topic = published[0].topic();
+        assert!(topic.starts_with("rpc/service/client-"));
+        assert!(topic.ends_with("/request"));
+
+        // The response is delivered to the requester only
+        let response_topic = topic.replace("/request", "/response");
+        assert!(transport
+            .subscriptions()
+            .iter()
+            .any(|s| s.topic() == response_topic));
+        transport.inject_message(Message::new(response_topic, "response", 1));
+        assert_eq!(request.await.unwrap().unwrap().payload(), b"response");
+        while let Ok(event) = rx.try_recv() {
+            assert!(!matches!(event, Event::Rx(_)));
+        }
+
+        // The response topic is unsubscribed from once the request is done
+        assert!(transport.subscriptions().is_empty());
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn responder_unsubscribes() {
+        use crate::{transport::start_client, SubscriptionBuilder};
+
+        let (client, transport) = start_client(Default::default()).await;
+        let subscription = |topic: &str| {
+            SubscriptionBuilder::default()
+                .topic(topic.to_owned())
+                .build()
+                .unwrap()
+        };
+
+        let stopped = Responder::new(&client, subscription("a"), |_| None);
+        let dropped = Responder::new(&client, subscription("b"), |_| None);
+        assert_eq!(transport.subscriptions().len(), 2);
+
+        stopped.stop();
+        drop(dropped);
+        assert!(transport.subscriptions().is_empty());
+
+        // The filters are not subscribed to again on reconnecting
+        transport.simulate_connection_lost();
+        transport.simulate_connect();
+        assert!(transport.subscriptions().is_empty());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::{
+    ClientConfig, ConnectOptions, ConnectOptionsBuilder, Credentials, PemSource, TlsConfig,
+};
+#[cfg(feature = "paho")]
+use paho_mqtt::{CreateOptions, CreateOptionsBuilder, PersistenceType};
+use serde::Deserialize;
+use std::{
+    env::VarError,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Connection and client configuration, loaded from a file and/or the environment.
+///
+/// Settings can be loaded from TOML or YAML, every field is optional:
+///
+/// ```toml
+/// broker = "tcp://localhost:1883"
+/// client_id = "my-service"
+/// mqtt_version = 5
+/// username = "me"
+/// password = "my_password"
+/// keep_alive = "30s"
+///
+/// [tls]
+/// ca = { file = "/etc/mqtt/ca.pem" }
+/// client_cert = { file = "/etc/mqtt/client.pem" }
+/// client_key = { file = "/etc/mqtt/client.key" }
+///
+/// [reconnect]
+/// min_interval = "1s"
+/// max_interval = "1m"
+///
+/// [client]
+/// channel_size = 64
+/// rpc_mode = { topic_convention = { prefix = "rpc" } }
+///
+/// [client.will_message]
+/// topic = "status/my-service"
+/// payload = "offline"
+/// qos = 1
+/// retain = true
+///
+/// [[client.subscriptions]]
+/// topic = "sensors/+/temperature"
+/// qos = 1
+///
+/// [[client.subscriptions]]
+/// topic = "jobs/#"
+/// shared_group = "workers"
+/// retain_handling = "dont_send"
+/// ```
+///
+/// The following environment variables override the corresponding settings when loaded with
+/// [`Settings::load`] or [`Settings::with_env_overrides`]:
+///
+/// | Variable | Setting |
+/// |---|---|
+/// | `MQTT_BROKER` | `broker` |
+/// | `MQTT_CLIENT_ID` | `client_id` |
+/// | `MQTT_VERSION` | `mqtt_version` |
+/// | `MQTT_USERNAME` | `username` |
+/// | `MQTT_PASSWORD` | `password` |
+/// | `MQTT_KEEP_ALIVE` | `keep_alive` |
+/// | `MQTT_CLEAN_SESSION` | `clean_session` |
+/// | `MQTT_TLS_CA_FILE` | `tls.ca.file` |
+/// | `MQTT_TLS_CERT_FILE` | `tls.client_cert.file` |
+/// | `MQTT_TLS_KEY_FILE` | `tls.client_key.file` |
+/// | `MQTT_RECONNECT` | `reconnect.enabled` |
+/// | `MQTT_RECONNECT_MIN_INTERVAL` | `reconnect.min_interval` |
+/// | `MQTT_RECONNECT_MAX_INTERVAL` | `reconnect.max_interval` |
+/// | `MQTT_CHANNEL_SIZE` | `client.channel_size` |
+/// | `MQTT_RESPONSE_TOPIC_PREFIX` | `client.response_topic_prefix` |
+/// | `MQTT_METRICS_PREFIX` | `client.metrics_prefix` |
+/// | `MQTT_METRICS_SERVER_ADDRESS` | `client.metrics_server_address` |
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// URI of the broker.
+    pub broker: String,
+
+    /// Client ID, a random ID is assigned by the broker if empty.
+    pub client_id: String,
+
+    /// MQTT version to connect with, `0` tries v3.1.1 then v3.1.
+    pub mqtt_version: u32,
+
+    /// User name to authenticate with.
+    pub username: Option<String>,
+
+    /// Password to authenticate with.
+    pub password: Option<String>,
+
+    /// Interval at which the connection is checked.
+    #[serde(with = "humantime_serde")]
+    pub keep_alive: Duration,
+
+    /// Start with a new session, discarding any state the broker has kept for the client ID.
+    pub clean_session: bool,
+
+    /// Directory in which messages being sent are persisted, persistence is disabled if unset.
+    ///
+    /// Only used by the paho backend.
+    pub persistence_dir: Option<PathBuf>,
+
+    /// TLS configuration, TLS is used if set and the broker URI uses a TLS scheme.
+    pub tls: Option<TlsConfig>,
+
+    /// Reconnection settings, also used when the client reconnects itself.
+    pub reconnect: ReconnectSettings,
+
+    /// Client configuration.
+    pub client: ClientConfig,
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The password is not included in debug output
+        f.debug_struct("Settings")
+            .field("broker", &self.broker)
+            .field("client_id", &self.client_id)
+            .field("mqtt_version", &self.mqtt_version)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .field("keep_alive", &self.keep_alive)
+            .field("clean_session", &self.clean_session)
+            .field("persistence_dir", &self.persistence_dir)
+            .field("tls", &self.tls)
+            .field("reconnect", &self.reconnect)
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            broker: "tcp://localhost:1883".into(),
+            client_id: String::new(),
+            mqtt_version: 0,
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            persistence_dir: None,
+            tls: None,
+            reconnect: ReconnectSettings::default(),
+            client: ClientConfig::default(),
+        }
+    }
+}
+
+/// Automatic reconnection settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectSettings {
+    /// Reconnect when the connection is lost.
+    pub enabled: bool,
+
+    /// Time to wait before the first attempt to reconnect.
+    #[serde(with = "humantime_serde")]
+    pub min_interval: Duration,
+
+    /// Longest time to wait between attempts, the interval doubles after each failed attempt.
+    #[serde(with = "humantime_serde")]
+    pub max_interval: Duration,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Settings {
+    /// Load settings from a TOML or YAML file, depending on its extension, then apply overrides
+    /// from the environment.
+    pub fn load(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let settings = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml(&contents)?,
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents)?,
+            _ => {
+                return Err(crate::Error::InvalidConfig(format!(
+                    "unknown format of configuration file \"{}\"",
+                    path.display()
+                )))
+            }
+        };
+
+        settings.with_env_overrides()
+    }
+
+    /// Load settings from the environment only.
+    pub fn from_env() -> crate::Result<Self> {
+        Self::default().with_env_overrides()
+    }
+
+    /// Parse settings from TOML.
+    pub fn from_toml(s: &str) -> crate::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Parse settings from YAML.
+    pub fn from_yaml(s: &str) -> crate::Result<Self> {
+        Ok(serde_yaml::from_str(s)?)
+    }
+
+    /// Override settings with the values of `MQTT_*` environment variables.
+    pub fn with_env_overrides(mut self) -> crate::Result<Self> {
+        if let Some(broker) = env("MQTT_BROKER")? {
+            self.broker = broker;
+        }
+        if let Some(client_id) = env("MQTT_CLIENT_ID")? {
+            self.client_id = client_id;
+        }
+        if let Some(version) = env("MQTT_VERSION")? {
+            self.mqtt_version = version;
+        }
+        if let Some(username) = env("MQTT_USERNAME")? {
+            self.username = Some(username);
+        }
+        if let Some(password) = env("MQTT_PASSWORD")? {
+            self.password = Some(password);
+        }
+        if let Some(keep_alive) = env::<humantime::Duration>("MQTT_KEEP_ALIVE")? {
+            self.keep_alive = keep_alive.into();
+        }
+        if let Some(clean_session) = env("MQTT_CLEAN_SESSION")? {
+            self.clean_session = clean_session;
+        }
+
+        if let Some(ca_file) = env("MQTT_TLS_CA_FILE")? {
+            self.tls.get_or_insert_with(Default::default).ca = Some(PemSource::File(ca_file));
+        }
+        if let Some(cert_file) = env("MQTT_TLS_CERT_FILE")? {
+            self.tls.get_or_insert_with(Default::default).client_cert =
+                Some(PemSource::File(cert_file));
+        }
+        if let Some(key_file) = env("MQTT_TLS_KEY_FILE")? {
+            self.tls.get_or_insert_with(Default::default).client_key =
+                Some(PemSource::File(key_file));
+        }
+
+        if let Some(enabled) = env("MQTT_RECONNECT")? {
+            self.reconnect.enabled = enabled;
+        }
+        if let Some(interval) = env::<humantime::Duration>("MQTT_RECONNECT_MIN_INTERVAL")? {
+            self.reconnect.min_interval = interval.into();
+        }
+        if let Some(interval) = env::<humantime::Duration>("MQTT_RECONNECT_MAX_INTERVAL")? {
+            self.reconnect.max_interval = interval.into();
+        }
+
+        if let Some(channel_size) = env("MQTT_CHANNEL_SIZE")? {
+            self.client.channel_size = channel_size;
+        }
+        if let Some(prefix) = env("MQTT_RESPONSE_TOPIC_PREFIX")? {
+            self.client.response_topic_prefix = prefix;
+        }
+        #[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+        if let Some(prefix) = env("MQTT_METRICS_PREFIX")? {
+            self.client.metrics_prefix = prefix;
+        }
+        #[cfg(feature = "metrics-server")]
+        if let Some(address) = env("MQTT_METRICS_SERVER_ADDRESS")? {
+            self.client.metrics_server_address = Some(address);
+        }
+
+        Ok(self)
+    }
+
+    /// Options to create the client with using the paho backend.
+    #[cfg(feature = "paho")]
+    pub fn create_options(&self) -> CreateOptions {
+        let persistence = match &self.persistence_dir {
+            Some(dir) => PersistenceType::FilePath(dir.clone()),
+            None => PersistenceType::None,
+        };
+
+        CreateOptionsBuilder::new()
+            .server_uri(&self.broker)
+            .client_id(&self.client_id)
+            .mqtt_version(self.mqtt_version)
+            .persistence(persistence)
+            .finalize()
+    }
+
+    /// Options to connect to the broker with.
+    pub fn connect_options(&self) -> crate::Result<ConnectOptions> {
+        let credentials = Credentials {
+            username: self.username.clone(),
+            password: self.password.clone(),
+        };
+
+        let mut builder = self.connect_options_builder(&credentials)?;
+        if self.reconnect.enabled {
+            builder.automatic_reconnect(self.reconnect.min_interval, self.reconnect.max_interval);
+        }
+
+        Ok(builder.build().unwrap())
+    }
+
+    /// Options to connect to the broker with using the given credentials, for use with
+    /// [`Client::start_with_credentials`](crate::Client::start_with_credentials).
+    ///
+    /// Automatic reconnection is not enabled, as the client reconnects itself.
+    pub fn connect_options_with(&self, credentials: &Credentials) -> crate::Result<ConnectOptions> {
+        Ok(self.connect_options_builder(credentials)?.build().unwrap())
+    }
+
+    fn connect_options_builder(
+        &self,
+        credentials: &Credentials,
+    ) -> crate::Result<ConnectOptionsBuilder> {
+        let mut builder = ConnectOptionsBuilder::default();
+        builder
+            .mqtt_version(self.mqtt_version)
+            .keep_alive(self.keep_alive)
+            .clean_session(self.clean_session)
+            .credentials(credentials.clone());
+
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+            builder.tls(tls.clone());
+        }
+
+        self.client.apply_will(&mut builder);
+
+        Ok(builder)
+    }
+
+    /// Configuration to create the client with.
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            reconnect_interval: self.reconnect.min_interval,
+            max_reconnect_interval: self.reconnect.max_interval,
+            ..self.client.clone()
+        }
+    }
+
+    /// Create a client using these settings, with the paho backend if the `paho` feature is
+    /// enabled and the rumqttc backend otherwise.
+    ///
+    /// The client is started with [`Settings::connect_options`].
+    #[cfg(feature = "paho")]
+    pub fn client(&self) -> crate::Result<crate::Client> {
+        crate::Client::new(self.create_options(), self.client_config())
+    }
+
+    /// Create a client using these settings, with the paho backend if the `paho` feature is
+    /// enabled and the rumqttc backend otherwise.
+    ///
+    /// The client is started with [`Settings::connect_options`].
+    #[cfg(all(feature = "rumqttc", not(feature = "paho")))]
+    pub fn client(&self) -> crate::Result<crate::Client> {
+        let transport =
+            crate::RumqttcTransport::with_uri(&self.broker, &self.client_id, self.mqtt_version)?;
+        Ok(crate::Client::with_transport(
+            transport,
+            self.client_config(),
+        ))
+    }
+}
+
+/// Get and parse the value of an environment variable, if it is set.
+fn env<T>(name: &str) -> crate::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| crate::Error::InvalidConfig(format!("{}: {}", name, e))),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(crate::Error::InvalidConfig(format!("{}: {}", name, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serialises tests that set environment variables, which are shared by the whole process.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Call `f` with the given environment variables set, removing them afterwards.
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let result = f();
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        result
+    }
+
+    const TOML: &str = r#"
+        broker = "tcp://broker:1883"
+        client_id = "test"
+        mqtt_version = 5
+        username = "me"
+        password = "secret"
+        keep_alive = "30s"
+
+        [reconnect]
+        min_interval = "2s"
+        max_interval = "1m"
+
+        [client]
+        channel_size = 64
+
+        [client.will_message]
+        topic = "status/test"
+        payload = "offline"
+        qos = 1
+        retain = true
+
+        [[client.subscriptions]]
+        topic = "sensors/+/temperature"
+        qos = 1
+    "#;
+
+    #[test]
+    fn toml() {
+        let settings = Settings::from_toml(TOML).unwrap();
+
+        assert_eq!(settings.broker, "tcp://broker:1883");
+        assert_eq!(settings.client_id, "test");
+        assert_eq!(settings.mqtt_version, 5);
+        assert_eq!(settings.username.as_deref(), Some("me"));
+        assert_eq!(settings.password.as_deref(), Some("secret"));
+        assert_eq!(settings.keep_alive, Duration::from_secs(30));
+        assert!(settings.clean_session);
+        assert_eq!(settings.reconnect.min_interval, Duration::from_secs(2));
+        assert_eq!(settings.reconnect.max_interval, Duration::from_secs(60));
+        assert_eq!(settings.client.channel_size, 64);
+        assert_eq!(settings.client.subscriptions.len(), 1);
+        assert_eq!(
+            settings.client.subscriptions[0].topic(),
+            "sensors/+/temperature"
+        );
+        assert_eq!(settings.client.subscriptions[0].qos(), 1);
+
+        let will = settings.client.will_message.unwrap();
+        assert_eq!(will.topic(), "status/test");
+        assert_eq!(will.payload_str(), "offline");
+        assert_eq!(will.qos(), 1);
+        assert!(will.retained());
+    }
+
+    #[test]
+    fn yaml() {
+        let settings = Settings::from_yaml(
+            "
+            broker: tcp://broker:1883
+            keep_alive: 10s
+            clean_session: false
+            reconnect:
+              enabled: false
+            client:
+              response_topic_prefix: replies
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(settings.broker, "tcp://broker:1883");
+        assert_eq!(settings.keep_alive, Duration::from_secs(10));
+        assert!(!settings.clean_session);
+        assert!(!settings.reconnect.enabled);
+        assert_eq!(settings.client.response_topic_prefix, "replies");
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(Settings::from_toml("brokr = \"tcp://broker:1883\"").is_err());
+        assert!(Settings::from_yaml("reconnect:\n  enable: false").is_err());
+    }
+
+    #[test]
+    fn env_overrides() {
+        let settings = with_env(
+            &[
+                ("MQTT_BROKER", "ssl://other:8883"),
+                ("MQTT_PASSWORD", "other"),
+                ("MQTT_KEEP_ALIVE", "5s"),
+                ("MQTT_CLEAN_SESSION", "false"),
+                ("MQTT_TLS_CA_FILE", "/etc/mqtt/ca.pem"),
+                ("MQTT_RECONNECT_MAX_INTERVAL", "10s"),
+                ("MQTT_CHANNEL_SIZE", "8"),
+            ],
+            || Settings::from_toml(TOML).unwrap().with_env_overrides(),
+        )
+        .unwrap();
+
+        assert_eq!(settings.broker, "ssl://other:8883");
+        assert_eq!(settings.password.as_deref(), Some("other"));
+        assert_eq!(settings.keep_alive, Duration::from_secs(5));
+        assert!(!settings.clean_session);
+        assert_eq!(settings.reconnect.max_interval, Duration::from_secs(10));
+        assert_eq!(settings.client.channel_size, 8);
+
+        let tls = settings.tls.unwrap();
+        assert!(matches!(&tls.ca, Some(PemSource::File(f)) if f == Path::new("/etc/mqtt/ca.pem")));
+        assert!(tls.client_cert.is_none());
+
+        // Settings without a variable are kept
+        assert_eq!(settings.client_id, "test");
+        assert_eq!(settings.username.as_deref(), Some("me"));
+        assert_eq!(settings.reconnect.min_interval, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn env_invalid() {
+        for (name, value) in [
+            ("MQTT_VERSION", "five"),
+            ("MQTT_KEEP_ALIVE", "30"),
+            ("MQTT_CLEAN_SESSION", "no"),
+            ("MQTT_CHANNEL_SIZE", "-1"),
+        ] {
+            let result = with_env(&[(name, value)], Settings::from_env);
+            match result {
+                Err(crate::Error::InvalidConfig(message)) => {
+                    assert!(message.starts_with(name), "{}", message)
+                }
+                other => panic!("{}={} should be invalid: {:?}", name, value, other),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_options() {
+        let settings = Settings::from_toml(TOML).unwrap();
+        let options = settings.connect_options().unwrap();
+
+        assert_eq!(options.mqtt_version, 5);
+        assert_eq!(options.keep_alive, Duration::from_secs(30));
+        assert_eq!(options.credentials.username.as_deref(), Some("me"));
+        assert_eq!(options.credentials.password.as_deref(), Some("secret"));
+        assert_eq!(
+            options.automatic_reconnect,
+            Some((Duration::from_secs(2), Duration::from_secs(60)))
+        );
+        assert_eq!(options.will_message.unwrap().topic(), "status/test");
+
+        let settings = Settings {
+            reconnect: ReconnectSettings {
+                enabled: false,
+                ..Default::default()
+            },
+            ..settings
+        };
+        assert!(settings
+            .connect_options()
+            .unwrap()
+            .automatic_reconnect
+            .is_none());
+    }
+
+    #[test]
+    fn connect_options_with() {
+        let settings = Settings::from_toml(TOML).unwrap();
+        let credentials = Credentials {
+            username: Some("token".into()),
+            password: None,
+        };
+        let options = settings.connect_options_with(&credentials).unwrap();
+
+        // The client reconnects itself when started with credentials
+        assert!(options.automatic_reconnect.is_none());
+        assert_eq!(options.credentials.username.as_deref(), Some("token"));
+        assert!(options.credentials.password.is_none());
+    }
+
+    #[test]
+    fn client_config() {
+        let config = Settings::from_toml(TOML).unwrap().client_config();
+
+        assert_eq!(config.reconnect_interval, Duration::from_secs(2));
+        assert_eq!(config.max_reconnect_interval, Duration::from_secs(60));
+        assert_eq!(config.channel_size, 64);
+    }
+}
@@ -1,12 +1,118 @@
+use crate::topic;
 use derive_builder::Builder;
 
 /// MQTT subscription.
+///
+/// The no local, retain as published, retain handling and subscription identifier options are
+/// only used when the client is created for MQTT v5.
+///
+/// With the `config-file` feature a subscription can be deserialized, its topic filter, QoS and
+/// subscription identifier are validated when it is.
 #[derive(Builder, Debug, Clone)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(try_from = "SubscriptionConfig")
+)]
 pub struct Subscription {
     pub(crate) topic: String,
 
     #[builder(default = "0")]
     pub(crate) qos: i32,
+
+    /// Do not receive messages published by this client.
+    #[builder(default)]
+    pub(crate) no_local: bool,
+
+    /// Keep the retain flag of messages as it was when they were published.
+    #[builder(default)]
+    pub(crate) retain_as_published: bool,
+
+    /// When retained messages are sent on subscribe.
+    #[builder(default)]
+    pub(crate) retain_handling: RetainHandling,
+
+    /// Identifier included in messages received as a result of this subscription.
+    #[builder(default, setter(strip_option))]
+    pub(crate) subscription_id: Option<i32>,
+
+    /// Name of the group to share this subscription with.
+    ///
+    /// Messages matching the topic are delivered to only one of the clients subscribed with the
+    /// same group.
+    #[builder(default, setter(into, strip_option))]
+    pub(crate) shared_group: Option<String>,
+}
+
+impl Subscription {
+    /// Check if a topic matches the topic filter of this subscription.
+    pub fn matches(&self, topic: &str) -> bool {
+        topic::matches(&self.topic, topic)
+    }
+
+    /// Get the topic filter as it is subscribed to on the broker.
+    ///
+    /// This is the topic filter prefixed with `$share/<group>/` if the subscription is shared.
+    pub fn broker_topic(&self) -> String {
+        match &self.shared_group {
+            Some(group) => format!("$share/{}/{}", group, self.topic),
+            None => self.topic.clone(),
+        }
+    }
+
+    /// Get the topic filter.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Get the maximum QoS of messages received as a result of this subscription.
+    pub fn qos(&self) -> i32 {
+        self.qos
+    }
+
+    /// Get if messages published by this client are not received.
+    pub fn no_local(&self) -> bool {
+        self.no_local
+    }
+
+    /// Get if the retain flag of messages is kept as it was when they were published.
+    pub fn retain_as_published(&self) -> bool {
+        self.retain_as_published
+    }
+
+    /// Get when retained messages are sent on subscribe.
+    pub fn retain_handling(&self) -> RetainHandling {
+        self.retain_handling
+    }
+
+    /// Get the identifier included in messages received as a result of this subscription.
+    pub fn subscription_id(&self) -> Option<i32> {
+        self.subscription_id
+    }
+
+    /// Get the name of the group this subscription is shared with.
+    pub fn shared_group(&self) -> Option<&str> {
+        self.shared_group.as_deref()
+    }
+}
+
+/// When retained messages are sent by the broker on subscribe.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum RetainHandling {
+    /// Send retained messages every time the subscription is made.
+    #[default]
+    SendOnSubscribe,
+
+    /// Send retained messages only if the subscription did not already exist.
+    SendOnNew,
+
+    /// Do not send retained messages.
+    DontSend,
 }
 
 impl SubscriptionBuilder {
@@ -25,3 +131,151 @@ impl SubscriptionBuilder {
         self.qos(2)
     }
 }
+
+/// Representation of a [`Subscription`] in a configuration file.
+#[cfg(feature = "config-file")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscriptionConfig {
+    topic: String,
+    #[serde(default)]
+    qos: i32,
+    #[serde(default)]
+    no_local: bool,
+    #[serde(default)]
+    retain_as_published: bool,
+    #[serde(default)]
+    retain_handling: RetainHandling,
+    subscription_id: Option<i32>,
+    shared_group: Option<String>,
+}
+
+#[cfg(feature = "config-file")]
+impl TryFrom<SubscriptionConfig> for Subscription {
+    type Error = String;
+
+    fn try_from(config: SubscriptionConfig) -> Result<Self, Self::Error> {
+        let subscription = Self {
+            topic: config.topic,
+            qos: config.qos,
+            no_local: config.no_local,
+            retain_as_published: config.retain_as_published,
+            retain_handling: config.retain_handling,
+            subscription_id: config.subscription_id,
+            shared_group: config.shared_group,
+        };
+
+        if !topic::is_valid_filter(&subscription.broker_topic()) {
+            return Err(format!(
+                "invalid topic filter \"{}\"",
+                subscription.broker_topic()
+            ));
+        }
+        if !(0..=2).contains(&subscription.qos) {
+            return Err(format!("invalid QoS {}", subscription.qos));
+        }
+        if let Some(id) = subscription.subscription_id {
+            if !(1..=268_435_455).contains(&id) {
+                return Err(format!("invalid subscription identifier {}", id));
+            }
+        }
+
+        Ok(subscription)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let subscription = SubscriptionBuilder::default()
+            .topic("a/+".to_owned())
+            .build()
+            .unwrap();
+
+        assert_eq!(subscription.qos(), 0);
+        assert!(!subscription.no_local());
+        assert!(!subscription.retain_as_published());
+        assert_eq!(
+            subscription.retain_handling(),
+            RetainHandling::SendOnSubscribe
+        );
+        assert_eq!(subscription.subscription_id(), None);
+        assert_eq!(subscription.shared_group(), None);
+        assert_eq!(subscription.broker_topic(), "a/+");
+    }
+
+    #[test]
+    fn options() {
+        let subscription = SubscriptionBuilder::default()
+            .topic("a/+".to_owned())
+            .qos_at_least_once()
+            .no_local(true)
+            .retain_as_published(true)
+            .retain_handling(RetainHandling::DontSend)
+            .subscription_id(42)
+            .build()
+            .unwrap();
+
+        assert_eq!(subscription.qos(), 1);
+        assert!(subscription.no_local());
+        assert!(subscription.retain_as_published());
+        assert_eq!(subscription.retain_handling(), RetainHandling::DontSend);
+        assert_eq!(subscription.subscription_id(), Some(42));
+    }
+
+    #[test]
+    fn shared() {
+        let subscription = SubscriptionBuilder::default()
+            .topic("a/+".to_owned())
+            .shared_group("workers")
+            .build()
+            .unwrap();
+
+        assert_eq!(subscription.shared_group(), Some("workers"));
+        assert_eq!(subscription.broker_topic(), "$share/workers/a/+");
+
+        // Topics are matched against the filter without the group
+        assert!(subscription.matches("a/b"));
+        assert!(!subscription.matches("b/c"));
+    }
+
+    #[cfg(feature = "config-file")]
+    #[test]
+    fn deserialize() {
+        let subscription: Subscription = toml::from_str(
+            r#"
+                topic = "a/#"
+                qos = 2
+                no_local = true
+                retain_handling = "send_on_new"
+                subscription_id = 7
+                shared_group = "workers"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(subscription.broker_topic(), "$share/workers/a/#");
+        assert_eq!(subscription.qos(), 2);
+        assert!(subscription.no_local());
+        assert_eq!(subscription.retain_handling(), RetainHandling::SendOnNew);
+        assert_eq!(subscription.subscription_id(), Some(7));
+
+        for invalid in [
+            r#"topic = "a/#/b""#,
+            r#"topic = "a"
+               qos = 3"#,
+            r#"topic = "a"
+               subscription_id = 0"#,
+            r#"topic = "a"
+               shared_group = "a+""#,
+        ] {
+            assert!(
+                toml::from_str::<Subscription>(invalid).is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+}
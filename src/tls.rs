@@ -0,0 +1,242 @@
+use derive_builder::Builder;
+#[cfg(feature = "paho")]
+use paho_mqtt::{SslOptions, SslOptionsBuilder};
+use std::{fmt, path::PathBuf};
+#[cfg(feature = "in-memory-pem")]
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+#[cfg(feature = "in-memory-pem")]
+use tempfile::TempDir;
+
+/// TLS configuration.
+///
+/// Certificates and keys given as files are read by the backend every time it connects or
+/// reconnects automatically, so replacing the files (e.g. when certificates are renewed) takes
+/// effect on the next reconnect.
+///
+/// Overriding the server name sent with SNI and verified against the broker certificate is not
+/// supported by either backend, the host of the server URI is always used.
+///
+/// The paho backend reads certificates and keys from files. With the `in-memory-pem` feature,
+/// certificates and keys given as in-memory PEM are written to a private temporary directory when
+/// the options are converted, which is removed when the last clone of the configuration is
+/// dropped. The configuration must therefore be kept for as long as the client may (re)connect.
+///
+/// The rumqttc backend does not support encrypted client keys or skipping verification, and needs
+/// a CA to authenticate with a client certificate.
+#[derive(Builder, Clone, Default)]
+#[builder(default)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct TlsConfig {
+    /// Certificate authorities trusted to sign the broker certificate.
+    #[builder(setter(strip_option))]
+    pub(crate) ca: Option<PemSource>,
+
+    /// Client certificate, which may also contain the client private key.
+    #[builder(setter(strip_option))]
+    pub(crate) client_cert: Option<PemSource>,
+
+    /// Client private key.
+    #[builder(setter(strip_option))]
+    pub(crate) client_key: Option<PemSource>,
+
+    /// Password of the client private key.
+    #[builder(setter(into, strip_option))]
+    pub(crate) client_key_password: Option<String>,
+
+    /// Protocols to offer using ALPN.
+    pub(crate) alpn_protocols: Vec<String>,
+
+    /// Do not verify the broker certificate, for development only.
+    pub(crate) insecure_skip_verify: bool,
+
+    /// Directory that in-memory PEM is written to.
+    #[cfg(feature = "in-memory-pem")]
+    #[builder(setter(skip))]
+    #[cfg_attr(feature = "config-file", serde(skip))]
+    pem_dir: Arc<Mutex<Option<TempDir>>>,
+}
+
+/// Source of PEM encoded certificates or keys.
+#[derive(Clone)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum PemSource {
+    /// Path of a PEM file.
+    File(PathBuf),
+
+    /// PEM data, which requires the `in-memory-pem` feature to be used with the paho backend.
+    Pem(String),
+}
+
+impl fmt::Debug for PemSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keys are not included in debug output
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Pem(_) => f.debug_tuple("Pem").field(&"..").finish(),
+        }
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The key password is not included in debug output
+        f.debug_struct("TlsConfig")
+            .field("ca", &self.ca)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field(
+                "client_key_password",
+                &self.client_key_password.as_ref().map(|_| ".."),
+            )
+            .field("alpn_protocols", &self.alpn_protocols)
+            .field("insecure_skip_verify", &self.insecure_skip_verify)
+            .finish()
+    }
+}
+
+impl PemSource {
+    pub(crate) fn read(&self) -> crate::Result<String> {
+        match self {
+            Self::File(path) => {
+                std::fs::read_to_string(path).map_err(|source| crate::Error::TlsFileError {
+                    path: path.clone(),
+                    source,
+                })
+            }
+            Self::Pem(pem) => Ok(pem.clone()),
+        }
+    }
+
+    /// Check that the source contains a PEM block of the given label.
+    fn validate(&self, what: &str, label: &str) -> crate::Result<()> {
+        let pem = self.read()?;
+
+        let found = pem.lines().any(|line| {
+            line.strip_prefix("-----BEGIN ")
+                .and_then(|l| l.strip_suffix("-----"))
+                .is_some_and(|l| l.ends_with(label))
+        });
+
+        if found {
+            Ok(())
+        } else {
+            Err(crate::Error::InvalidTlsConfig(match self {
+                Self::File(path) => {
+                    format!("{} file \"{}\" has no {}", what, path.display(), label)
+                }
+                Self::Pem(_) => format!("{} PEM has no {}", what, label),
+            }))
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Check that the configured certificates and keys can be read and contain PEM data.
+    pub fn validate(&self) -> crate::Result<()> {
+        if let Some(ca) = &self.ca {
+            ca.validate("CA", "CERTIFICATE")?;
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                cert.validate("Client certificate", "CERTIFICATE")?;
+                key.validate("Client key", "PRIVATE KEY")?;
+            }
+            (Some(cert), None) => {
+                cert.validate("Client certificate", "CERTIFICATE")?;
+                cert.validate("Client certificate", "PRIVATE KEY")?;
+            }
+            (None, Some(_)) => {
+                return Err(crate::Error::InvalidTlsConfig(
+                    "client key given without a client certificate".into(),
+                ))
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Validate the configuration and create the TLS options to connect with using the paho
+    /// backend.
+    #[cfg(feature = "paho")]
+    pub fn ssl_options(&self) -> crate::Result<SslOptions> {
+        self.validate()?;
+
+        let mut builder = SslOptionsBuilder::new();
+
+        if let Some(ca) = &self.ca {
+            builder.trust_store(self.path(ca, "ca.pem")?)?;
+        }
+        if let Some(cert) = &self.client_cert {
+            builder.key_store(self.path(cert, "cert.pem")?)?;
+        }
+        if let Some(key) = &self.client_key {
+            builder.private_key(self.path(key, "key.pem")?)?;
+        }
+        if let Some(password) = &self.client_key_password {
+            builder.private_key_password(password);
+        }
+
+        if !self.alpn_protocols.is_empty() {
+            let protocols: Vec<&str> = self.alpn_protocols.iter().map(String::as_str).collect();
+            builder.alpn_protos(&protocols);
+        }
+
+        builder
+            .enable_server_cert_auth(!self.insecure_skip_verify)
+            .verify(!self.insecure_skip_verify);
+
+        Ok(builder.finalize())
+    }
+
+    /// Get the path of a file containing the PEM from a source.
+    #[cfg(feature = "paho")]
+    fn path(&self, source: &PemSource, name: &str) -> crate::Result<PathBuf> {
+        match source {
+            PemSource::File(path) => Ok(path.clone()),
+            #[cfg(feature = "in-memory-pem")]
+            PemSource::Pem(pem) => {
+                let mut dir = self.pem_dir.lock().unwrap();
+                let dir = match &mut *dir {
+                    Some(dir) => dir,
+                    None => dir.insert(tempfile::tempdir()?),
+                };
+
+                let path = dir.path().join(name);
+                write_private(&path, pem)?;
+                Ok(path)
+            }
+            #[cfg(not(feature = "in-memory-pem"))]
+            PemSource::Pem(_) => Err(crate::Error::InvalidTlsConfig(format!(
+                "in-memory PEM for {} requires the in-memory-pem feature",
+                name
+            ))),
+        }
+    }
+}
+
+/// Write a file that is readable only by the current user.
+#[cfg(feature = "in-memory-pem")]
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
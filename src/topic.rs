@@ -0,0 +1,148 @@
+/// Check if a topic name matches a subscription topic filter.
+///
+/// Supports the `+` (single level) and `#` (multi level) wildcards, and does not match topics
+/// starting with `$` against filters starting with a wildcard.
+///
+/// A shared subscription prefix (`$share/<group>/`) on the filter is ignored.
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    let filter = strip_share_prefix(filter);
+
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Check if a topic filter is valid.
+///
+/// Wildcards must occupy an entire level and `#` must be the last level. A shared subscription
+/// prefix must name a group that does not contain wildcards.
+pub(crate) fn is_valid_filter(filter: &str) -> bool {
+    let filter = match filter.strip_prefix("$share/") {
+        Some(shared) => match shared.split_once('/') {
+            Some((group, filter)) if !group.is_empty() && !group.contains(['+', '#']) => filter,
+            _ => return false,
+        },
+        None => filter,
+    };
+
+    let levels: Vec<&str> = filter.split('/').collect();
+
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, level)| match *level {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+/// Remove the shared subscription prefix (`$share/<group>/`) from a topic filter, if present.
+pub(crate) fn strip_share_prefix(filter: &str) -> &str {
+    filter
+        .strip_prefix("$share/")
+        .and_then(|f| f.split_once('/'))
+        .map_or(filter, |(_, f)| f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        for (filter, topic) in [
+            ("a/b", "a/b"),
+            ("a/+", "a/b"),
+            ("+/b", "a/b"),
+            ("a/+/c", "a/b/c"),
+            ("a/#", "a"),
+            ("a/#", "a/b/c"),
+            ("#", "a/b"),
+            ("+", "a"),
+            ("a/+", "a/"),
+            ("+/+", "/a"),
+            ("$SYS/#", "$SYS/uptime"),
+            ("$share/group/a/+", "a/b"),
+        ] {
+            assert!(matches(filter, topic), "{} should match {}", filter, topic);
+        }
+    }
+
+    #[test]
+    fn not_matching() {
+        for (filter, topic) in [
+            ("a/b", "a/c"),
+            ("a/b", "a/b/c"),
+            ("a/b/c", "a/b"),
+            ("a/+", "a/b/c"),
+            ("a/+", "a"),
+            ("+", "a/b"),
+            ("#", "$SYS/uptime"),
+            ("+/uptime", "$SYS/uptime"),
+            ("$share/group/a/+", "b/c"),
+        ] {
+            assert!(
+                !matches(filter, topic),
+                "{} should not match {}",
+                filter,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn valid_filters() {
+        for filter in [
+            "a",
+            "a/b",
+            "+",
+            "#",
+            "a/+/c",
+            "a/#",
+            "+/+/#",
+            "/",
+            "$SYS/#",
+            "$share/group/a/#",
+        ] {
+            assert!(is_valid_filter(filter), "{} should be valid", filter);
+        }
+    }
+
+    #[test]
+    fn invalid_filters() {
+        for filter in [
+            "",
+            "a/#/b",
+            "#/a",
+            "a+",
+            "a/b#",
+            "a/+b/c",
+            "$share/group",
+            "$share//a",
+            "$share/gr+oup/a",
+            "$share/group/",
+            "$share/group/a/#/b",
+        ] {
+            assert!(!is_valid_filter(filter), "{} should be invalid", filter);
+        }
+    }
+
+    #[test]
+    fn share_prefix() {
+        assert_eq!(strip_share_prefix("$share/group/a/b"), "a/b");
+        assert_eq!(strip_share_prefix("a/b"), "a/b");
+        assert_eq!(strip_share_prefix("$share/group"), "$share/group");
+    }
+}
@@ -0,0 +1,141 @@
+use crate::Message;
+use opentelemetry::{global, trace::TraceContextExt, Context};
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Add the trace context of the current span to the MQTT v5 user properties of a message.
+///
+/// The context is encoded using the globally configured text map propagator (e.g. the W3C trace
+/// context propagator, which adds a `traceparent` property). Messages that already carry a trace
+/// context are returned unchanged.
+pub fn inject_trace_context(mut msg: Message) -> Message {
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut fields)
+    });
+
+    let user_properties = &mut msg.properties.user_properties;
+    if fields
+        .keys()
+        .any(|key| user_properties.iter().any(|(k, _)| k == key))
+    {
+        return msg;
+    }
+
+    user_properties.extend(fields);
+    msg
+}
+
+/// Extract the trace context from the MQTT v5 user properties of a message.
+///
+/// A consumer can continue the trace of the producer of a message by setting the returned context
+/// as the parent of its span, using [`OpenTelemetrySpanExt::set_parent`].
+pub fn extract_trace_context(msg: &Message) -> Context {
+    let fields: HashMap<String, String> =
+        msg.properties().user_properties.iter().cloned().collect();
+    global::get_text_map_propagator(|propagator| propagator.extract(&fields))
+}
+
+/// Create the span for publishing a message, continuing the trace of its producer.
+pub(crate) fn publish_span(msg: &Message) -> Span {
+    let span = tracing::info_span!(
+        "publish",
+        otel.kind = "producer",
+        messaging.system = "mqtt",
+        messaging.destination.name = msg.topic(),
+    );
+    set_remote_parent(&span, msg);
+    span
+}
+
+/// Create the span for receiving a message, continuing the trace of its producer.
+pub(crate) fn receive_span(msg: &Message) -> Span {
+    let span = tracing::info_span!(
+        "receive",
+        otel.kind = "consumer",
+        messaging.system = "mqtt",
+        messaging.destination.name = msg.topic(),
+    );
+    set_remote_parent(&span, msg);
+    span
+}
+
+/// Make the trace context carried by a message the parent of a span.
+///
+/// Spans of messages without a trace context remain children of the current span.
+fn set_remote_parent(span: &Span, msg: &Message) {
+    let cx = extract_trace_context(msg);
+    if cx.span().span_context().is_valid() {
+        span.set_parent(cx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBuilder;
+    use opentelemetry::trace::{TraceId, TracerProvider as _};
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    /// Run a function with spans recorded using OpenTelemetry and the W3C trace context
+    /// propagator.
+    fn with_tracing(f: impl FnOnce()) {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        // The tracer only refers to its provider, which must be kept
+        let provider = TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    fn trace_id(span: &Span) -> TraceId {
+        span.context().span().span_context().trace_id()
+    }
+
+    #[test]
+    fn inject() {
+        with_tracing(|| {
+            let span = tracing::info_span!("producer");
+            let _guard = span.enter();
+
+            let msg = inject_trace_context(Message::new("a", "", 0));
+            let traceparent = msg.properties().user_property("traceparent").unwrap();
+            assert!(traceparent.contains(&trace_id(&span).to_string()));
+
+            // A trace context already carried by the message is kept
+            let msg = MessageBuilder::new("a", "")
+                .user_property("traceparent", TRACEPARENT)
+                .build();
+            assert_eq!(inject_trace_context(msg.clone()), msg);
+        });
+    }
+
+    #[test]
+    fn extract() {
+        with_tracing(|| {
+            let trace_id_of_msg = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+            let msg = MessageBuilder::new("a", "")
+                .user_property("traceparent", TRACEPARENT)
+                .build();
+
+            let cx = extract_trace_context(&msg);
+            assert_eq!(cx.span().span_context().trace_id(), trace_id_of_msg);
+
+            // The spans of the message continue its trace
+            assert_eq!(trace_id(&receive_span(&msg)), trace_id_of_msg);
+            assert_eq!(trace_id(&publish_span(&msg)), trace_id_of_msg);
+
+            // Spans of messages without a trace context are children of the current span
+            let parent = tracing::info_span!("parent");
+            let _guard = parent.enter();
+            let span = receive_span(&Message::new("a", "", 0));
+            assert_eq!(trace_id(&span), trace_id(&parent));
+        });
+    }
+}
@@ -0,0 +1,187 @@
+#[cfg(feature = "test-util")]
+mod mock;
+#[cfg(feature = "paho")]
+mod paho;
+#[cfg(feature = "rumqttc")]
+mod rumqttc;
+
+use crate::{Credentials, Message, Subscription, TlsConfig};
+use derive_builder::Builder;
+use std::{fmt, future::Future, pin::Pin, time::Duration};
+
+#[cfg(all(test, feature = "test-util"))]
+pub(crate) use self::mock::start_client;
+#[cfg(feature = "test-util")]
+pub use self::mock::MockTransport;
+#[cfg(feature = "rumqttc")]
+pub use self::rumqttc::RumqttcTransport;
+
+/// Connect using the default MQTT version of the transport.
+pub const MQTT_VERSION_DEFAULT: u32 = 0;
+
+/// MQTT v3.1.1.
+pub const MQTT_VERSION_3_1_1: u32 = 4;
+
+/// MQTT v5.
+pub const MQTT_VERSION_5: u32 = 5;
+
+/// Future returned by the operations of a [`Transport`].
+pub type TransportFuture<T> = Pin<Box<dyn Future<Output = crate::Result<T>> + Send>>;
+
+/// Handler for the events raised by a [`Transport`].
+pub type TransportEventHandler = Box<dyn Fn(&dyn Transport, TransportEvent) + Send + Sync>;
+
+/// Event raised by a [`Transport`].
+#[derive(Debug, Clone)]
+pub enum TransportEvent {
+    /// A connection to the broker was established, including when reconnecting.
+    Connected,
+
+    /// The broker closed the connection.
+    Disconnected(ReasonCode),
+
+    /// The connection to the broker was lost.
+    ConnectionLost,
+
+    /// A message was received.
+    Message(Message),
+}
+
+/// Reason code sent by the broker, as defined by MQTT v5.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReasonCode(pub u8);
+
+impl fmt::Display for ReasonCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+/// Options to connect to the broker with.
+#[derive(Builder, Debug, Clone)]
+#[builder(default)]
+pub struct ConnectOptions {
+    /// MQTT version to connect with, which must match the version the transport was created for.
+    ///
+    /// [`MQTT_VERSION_DEFAULT`] uses the version of the transport.
+    pub(crate) mqtt_version: u32,
+
+    /// Interval at which the connection is checked.
+    pub(crate) keep_alive: Duration,
+
+    /// Start with a new session, discarding any state the broker has kept for the client ID.
+    pub(crate) clean_session: bool,
+
+    /// Credentials to authenticate with.
+    pub(crate) credentials: Credentials,
+
+    /// Message published by the broker when the client disconnects unexpectedly.
+    #[builder(setter(strip_option))]
+    pub(crate) will_message: Option<Message>,
+
+    /// TLS configuration.
+    #[builder(setter(strip_option))]
+    pub(crate) tls: Option<TlsConfig>,
+
+    /// Shortest and longest time to wait between attempts to reconnect, when the transport
+    /// reconnects itself after the connection is lost.
+    #[builder(setter(custom))]
+    pub(crate) automatic_reconnect: Option<(Duration, Duration)>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            mqtt_version: MQTT_VERSION_DEFAULT,
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            credentials: Credentials::default(),
+            will_message: None,
+            tls: None,
+            automatic_reconnect: None,
+        }
+    }
+}
+
+impl ConnectOptionsBuilder {
+    /// Set the user name to authenticate with.
+    pub fn user_name(&mut self, user_name: impl Into<String>) -> &mut Self {
+        self.credentials
+            .get_or_insert_with(Default::default)
+            .username = Some(user_name.into());
+        self
+    }
+
+    /// Set the password to authenticate with.
+    pub fn password(&mut self, password: impl Into<String>) -> &mut Self {
+        self.credentials
+            .get_or_insert_with(Default::default)
+            .password = Some(password.into());
+        self
+    }
+
+    /// Reconnect when the connection is lost, waiting from `min_interval` up to `max_interval`
+    /// between attempts.
+    ///
+    /// Should not be used with [`Client::start_with_credentials`](crate::Client::start_with_credentials),
+    /// where the client reconnects itself.
+    pub fn automatic_reconnect(
+        &mut self,
+        min_interval: Duration,
+        max_interval: Duration,
+    ) -> &mut Self {
+        self.automatic_reconnect = Some(Some((min_interval, max_interval)));
+        self
+    }
+}
+
+/// Details of an established connection.
+#[derive(Debug, Clone)]
+pub struct ConnectResponse {
+    /// URI of the broker that was connected to.
+    pub server_uri: String,
+
+    /// MQTT version used for the connection.
+    pub mqtt_version: u32,
+
+    /// If the broker had a session for the client.
+    pub session_present: bool,
+}
+
+/// Connection to an MQTT broker used by a [`Client`](crate::Client).
+///
+/// Requests are made when the methods are called, the returned futures only wait for the
+/// outcome, so they may be dropped if the outcome is not of interest.
+pub trait Transport: Send + Sync {
+    /// Get the client ID.
+    fn client_id(&self) -> String;
+
+    /// Get the URI of the broker.
+    fn server_uri(&self) -> String;
+
+    /// Get the MQTT version the client uses.
+    fn mqtt_version(&self) -> u32;
+
+    /// Check if the client is currently connected.
+    fn is_connected(&self) -> bool;
+
+    /// Check if received messages are flagged when the broker redelivers them.
+    fn reports_redeliveries(&self) -> bool {
+        true
+    }
+
+    /// Set the handler that is called with every event, replacing any previous handler.
+    fn set_event_handler(&self, handler: TransportEventHandler);
+
+    /// Connect to the broker, or reconnect after the connection was lost.
+    fn connect(&self, options: ConnectOptions) -> TransportFuture<ConnectResponse>;
+
+    /// Queue a message for publishing, the returned future resolves once it is delivered.
+    fn publish(&self, msg: Message) -> crate::Result<TransportFuture<()>>;
+
+    /// Subscribe to a topic filter.
+    fn subscribe(&self, subscription: &Subscription) -> TransportFuture<()>;
+
+    /// Unsubscribe from a topic filter.
+    fn unsubscribe(&self, topic: &str) -> TransportFuture<()>;
+}
@@ -0,0 +1,270 @@
+use super::{
+    ConnectOptions, ConnectResponse, ReasonCode, Transport, TransportEvent, TransportEventHandler,
+    TransportFuture, MQTT_VERSION_5,
+};
+use crate::{Message, Subscription};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::Notify;
+
+/// In-memory [`Transport`] for testing code that uses a [`Client`](crate::Client) without a
+/// broker.
+///
+/// Clones share the same state, so a clone can be kept by a test to inject incoming messages,
+/// simulate changes of the connection and inspect what the client published and subscribed to.
+///
+/// Connecting always succeeds and raises [`TransportEvent::Connected`], publishing fails while
+/// disconnected. The options of the last connection are kept for inspection.
+#[derive(Clone)]
+pub struct MockTransport {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    client_id: String,
+    mqtt_version: AtomicU32,
+    connected: AtomicBool,
+    connect_options: Mutex<Option<ConnectOptions>>,
+    handler: Mutex<Option<Arc<TransportEventHandler>>>,
+
+    published: Mutex<Vec<Message>>,
+    published_notify: Notify,
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl MockTransport {
+    /// Create a disconnected transport using MQTT v5.
+    pub fn new(client_id: impl Into<String>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client_id: client_id.into(),
+                mqtt_version: AtomicU32::new(MQTT_VERSION_5),
+                connected: Default::default(),
+                connect_options: Default::default(),
+                handler: Default::default(),
+
+                published: Default::default(),
+                published_notify: Default::default(),
+                subscriptions: Default::default(),
+            }),
+        }
+    }
+
+    /// Set the MQTT version the transport reports using.
+    pub fn set_mqtt_version(&self, version: u32) {
+        self.inner.mqtt_version.store(version, Ordering::Relaxed);
+    }
+
+    fn raise(&self, event: TransportEvent) {
+        // Clone the handler so the lock is not held while it is called, as it may call back into
+        // the transport
+        let handler = self.inner.handler.lock().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(self, event);
+        }
+    }
+
+    /// Deliver a message to the client as if it was received from the broker.
+    pub fn inject_message(&self, msg: Message) {
+        self.raise(TransportEvent::Message(msg));
+    }
+
+    /// Simulate a connection (or reconnection) to the broker.
+    pub fn simulate_connect(&self) {
+        self.inner.connected.store(true, Ordering::Relaxed);
+        self.raise(TransportEvent::Connected);
+    }
+
+    /// Simulate the broker closing the connection.
+    pub fn simulate_disconnect(&self, reason: ReasonCode) {
+        self.inner.connected.store(false, Ordering::Relaxed);
+        self.raise(TransportEvent::Disconnected(reason));
+    }
+
+    /// Simulate the connection to the broker being lost.
+    pub fn simulate_connection_lost(&self) {
+        self.inner.connected.store(false, Ordering::Relaxed);
+        self.raise(TransportEvent::ConnectionLost);
+    }
+
+    /// Get the options the transport was last connected with.
+    pub fn connect_options(&self) -> Option<ConnectOptions> {
+        self.inner.connect_options.lock().unwrap().clone()
+    }
+
+    /// Get the messages published so far, in the order they were published.
+    pub fn published(&self) -> Vec<Message> {
+        self.inner.published.lock().unwrap().clone()
+    }
+
+    /// Wait until at least `count` messages have been published and return them.
+    ///
+    /// Messages are published from a task of the client, so they are not seen immediately after
+    /// they are sent.
+    pub async fn wait_for_published(&self, count: usize) -> Vec<Message> {
+        loop {
+            let notified = self.inner.published_notify.notified();
+
+            let published = self.published();
+            if published.len() >= count {
+                return published;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Remove and return the messages published so far.
+    pub fn take_published(&self) -> Vec<Message> {
+        std::mem::take(&mut *self.inner.published.lock().unwrap())
+    }
+
+    /// Get the currently active subscriptions.
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.inner.subscriptions.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn client_id(&self) -> String {
+        self.inner.client_id.clone()
+    }
+
+    fn server_uri(&self) -> String {
+        "mock://".into()
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        self.inner.mqtt_version.load(Ordering::Relaxed)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::Relaxed)
+    }
+
+    fn set_event_handler(&self, handler: TransportEventHandler) {
+        *self.inner.handler.lock().unwrap() = Some(Arc::new(handler));
+    }
+
+    fn connect(&self, options: ConnectOptions) -> TransportFuture<ConnectResponse> {
+        *self.inner.connect_options.lock().unwrap() = Some(options);
+        self.simulate_connect();
+
+        let response = ConnectResponse {
+            server_uri: self.server_uri(),
+            mqtt_version: self.mqtt_version(),
+            session_present: false,
+        };
+        Box::pin(async move { Ok(response) })
+    }
+
+    fn publish(&self, msg: Message) -> crate::Result<TransportFuture<()>> {
+        if !self.is_connected() {
+            return Err(crate::Error::NotConnected);
+        }
+
+        self.inner.published.lock().unwrap().push(msg);
+        self.inner.published_notify.notify_waiters();
+
+        Ok(Box::pin(async { Ok(()) }))
+    }
+
+    fn subscribe(&self, subscription: &Subscription) -> TransportFuture<()> {
+        let mut subscriptions = self.inner.subscriptions.lock().unwrap();
+        subscriptions.retain(|s| s.broker_topic() != subscription.broker_topic());
+        subscriptions.push(subscription.clone());
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn unsubscribe(&self, topic: &str) -> TransportFuture<()> {
+        self.inner
+            .subscriptions
+            .lock()
+            .unwrap()
+            .retain(|s| s.broker_topic() != topic);
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Start a client using a mock transport, for testing the features of the client.
+#[cfg(test)]
+pub(crate) async fn start_client(config: crate::ClientConfig) -> (crate::Client, MockTransport) {
+    let transport = MockTransport::new("client");
+    let client = crate::Client::with_transport(transport.clone(), config);
+    client
+        .start(crate::ConnectOptionsBuilder::default().build().unwrap())
+        .await
+        .unwrap();
+
+    (client, transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientConfigBuilder, Event, StatusEvent, SubscriptionBuilder};
+
+    #[tokio::test]
+    async fn publish() {
+        let (client, transport) = start_client(Default::default()).await;
+        assert!(transport.connect_options().is_some());
+
+        client.send(Message::new("a", "1", 1)).unwrap();
+        client.send(Message::new("b", "2", 0)).unwrap();
+
+        let published = transport.wait_for_published(2).await;
+        assert_eq!(published[0].topic(), "a");
+        assert_eq!(published[1].payload(), b"2");
+
+        assert_eq!(transport.take_published().len(), 2);
+        assert!(transport.published().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscriptions() {
+        let subscription = SubscriptionBuilder::default()
+            .topic("a/#".to_owned())
+            .build()
+            .unwrap();
+        let config = ClientConfigBuilder::default()
+            .subscriptions(vec![subscription])
+            .build()
+            .unwrap();
+        let (_client, transport) = start_client(config).await;
+        assert_eq!(transport.subscriptions()[0].topic(), "a/#");
+
+        // Subscriptions are made again on reconnecting
+        transport.simulate_connection_lost();
+        transport.unsubscribe("a/#").await.unwrap();
+        transport.simulate_connect();
+        assert_eq!(transport.subscriptions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let (client, transport) = start_client(Default::default()).await;
+        let mut rx = client.rx_channel();
+
+        transport.inject_message(Message::new("a", "1", 0));
+        transport.simulate_disconnect(ReasonCode(0x8B));
+        transport.simulate_connect();
+
+        assert!(matches!(rx.try_recv(), Ok(Event::Rx(msg)) if msg.topic() == "a"));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Event::Status(StatusEvent::Disconnected))
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Event::Status(StatusEvent::Connected))
+        ));
+
+        // Publishing fails while disconnected
+        transport.simulate_connection_lost();
+        assert!(transport.publish(Message::new("a", "", 0)).is_err());
+    }
+}
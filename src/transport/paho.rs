@@ -0,0 +1,202 @@
+use super::{
+    ConnectOptions, ConnectResponse, ReasonCode, Transport, TransportEvent, TransportEventHandler,
+    TransportFuture, MQTT_VERSION_5, MQTT_VERSION_DEFAULT,
+};
+use crate::{Message, RetainHandling, Subscription};
+use paho_mqtt::{AsyncClient, ConnectOptionsBuilder, Properties, PropertyCode, SubscribeOptions};
+use std::sync::Arc;
+
+impl Transport for AsyncClient {
+    fn client_id(&self) -> String {
+        AsyncClient::client_id(self)
+    }
+
+    fn server_uri(&self) -> String {
+        AsyncClient::server_uri(self)
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        AsyncClient::mqtt_version(self)
+    }
+
+    fn is_connected(&self) -> bool {
+        AsyncClient::is_connected(self)
+    }
+
+    fn reports_redeliveries(&self) -> bool {
+        // The duplicate flag of received messages is not exposed
+        false
+    }
+
+    fn set_event_handler(&self, handler: TransportEventHandler) {
+        let handler: Arc<TransportEventHandler> = Arc::new(handler);
+
+        let h = handler.clone();
+        self.set_connected_callback(move |c| h(c, TransportEvent::Connected));
+
+        let h = handler.clone();
+        self.set_disconnected_callback(move |c, _props, reason| {
+            h(c, TransportEvent::Disconnected(ReasonCode(reason as u8)))
+        });
+
+        let h = handler.clone();
+        self.set_connection_lost_callback(move |c| h(c, TransportEvent::ConnectionLost));
+
+        self.set_message_callback(move |c, msg| {
+            if let Some(msg) = msg {
+                handler(c, TransportEvent::Message(msg.into()));
+            }
+        });
+    }
+
+    fn connect(&self, options: ConnectOptions) -> TransportFuture<ConnectResponse> {
+        let options = match connect_options(&options, AsyncClient::mqtt_version(self)) {
+            Ok(options) => options,
+            Err(e) => return Box::pin(async { Err(e) }),
+        };
+        let token = AsyncClient::connect(self, Some(options));
+
+        Box::pin(async move {
+            let response = token.await?;
+            let connect = response
+                .connect_response()
+                .ok_or(paho_mqtt::Error::General("No connect response"))?;
+
+            Ok(ConnectResponse {
+                server_uri: connect.server_uri,
+                mqtt_version: connect.mqtt_version,
+                session_present: connect.session_present,
+            })
+        })
+    }
+
+    fn publish(&self, msg: Message) -> crate::Result<TransportFuture<()>> {
+        let token = self.try_publish(paho_mqtt::Message::try_from(&msg)?)?;
+
+        Ok(Box::pin(async move {
+            token.await?;
+            Ok(())
+        }))
+    }
+
+    fn subscribe(&self, subscription: &Subscription) -> TransportFuture<()> {
+        let topic = subscription.broker_topic();
+
+        let token = if AsyncClient::mqtt_version(self) < MQTT_VERSION_5 {
+            AsyncClient::subscribe(self, topic, subscription.qos)
+        } else {
+            let options = SubscribeOptions::new(
+                subscription.no_local,
+                subscription.retain_as_published,
+                match subscription.retain_handling {
+                    RetainHandling::SendOnSubscribe => {
+                        paho_mqtt::RetainHandling::SendRetainedOnSubscribe
+                    }
+                    RetainHandling::SendOnNew => paho_mqtt::RetainHandling::SendRetainedOnNew,
+                    RetainHandling::DontSend => paho_mqtt::RetainHandling::DontSendRetained,
+                },
+            );
+
+            let props = subscription.subscription_id.map(|id| {
+                let mut props = Properties::new();
+                props
+                    .push_int(PropertyCode::SubscriptionIdentifier, id)
+                    .expect("subscription identifier should be an integer property");
+                props
+            });
+
+            self.subscribe_with_options(topic, subscription.qos, options, props)
+        };
+
+        Box::pin(async move {
+            token.await?;
+            Ok(())
+        })
+    }
+
+    fn unsubscribe(&self, topic: &str) -> TransportFuture<()> {
+        let token = AsyncClient::unsubscribe(self, topic);
+
+        Box::pin(async move {
+            token.await?;
+            Ok(())
+        })
+    }
+}
+
+/// Convert the options to connect with, for a client created for `client_version`.
+///
+/// Connecting with an MQTT version other than the one the client was created for changes the
+/// version of the session, so the default version is resolved to that of the client.
+fn connect_options(
+    options: &ConnectOptions,
+    client_version: u32,
+) -> crate::Result<paho_mqtt::ConnectOptions> {
+    let mqtt_version = match options.mqtt_version {
+        MQTT_VERSION_DEFAULT => client_version,
+        version => version,
+    };
+
+    let mut builder = ConnectOptionsBuilder::with_mqtt_version(mqtt_version);
+    builder.keep_alive_interval(options.keep_alive);
+
+    if mqtt_version >= MQTT_VERSION_5 {
+        builder.clean_start(options.clean_session);
+    } else {
+        builder.clean_session(options.clean_session);
+    }
+
+    if let Some(username) = &options.credentials.username {
+        builder.user_name(username);
+    }
+    if let Some(password) = &options.credentials.password {
+        builder.password(password);
+    }
+
+    if let Some(will) = &options.will_message {
+        builder.will_message(paho_mqtt::Message::try_from(will)?);
+    }
+
+    if let Some(tls) = &options.tls {
+        builder.ssl_options(tls.ssl_options()?);
+    }
+
+    if let Some((min_interval, max_interval)) = options.automatic_reconnect {
+        builder.automatic_reconnect(min_interval, max_interval);
+    }
+
+    Ok(builder.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MQTT_VERSION_3_1_1;
+
+    #[test]
+    fn default_version() {
+        let mut builder = crate::ConnectOptionsBuilder::default();
+        builder.clean_session(false);
+        let options = builder.build().unwrap();
+
+        // The session keeps the version the client was created for
+        let converted = connect_options(&options, MQTT_VERSION_5).unwrap();
+        assert_eq!(converted.mqtt_version(), MQTT_VERSION_5);
+        assert!(!converted.clean_start());
+
+        let converted = connect_options(&options, MQTT_VERSION_3_1_1).unwrap();
+        assert_eq!(converted.mqtt_version(), MQTT_VERSION_3_1_1);
+        assert!(!converted.clean_session());
+    }
+
+    #[test]
+    fn explicit_version() {
+        let options = crate::ConnectOptionsBuilder::default()
+            .mqtt_version(MQTT_VERSION_3_1_1)
+            .build()
+            .unwrap();
+
+        let converted = connect_options(&options, MQTT_VERSION_5).unwrap();
+        assert_eq!(converted.mqtt_version(), MQTT_VERSION_3_1_1);
+    }
+}
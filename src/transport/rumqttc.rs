@@ -0,0 +1,1056 @@
+use super::{
+    ConnectOptions, ConnectResponse, ReasonCode, Transport, TransportEvent, TransportEventHandler,
+    TransportFuture, MQTT_VERSION_3_1_1, MQTT_VERSION_5, MQTT_VERSION_DEFAULT,
+};
+use crate::{Message, MessageProperties, PayloadFormat, RetainHandling, Subscription, TlsConfig};
+use rumqttc::{
+    v5::{
+        self,
+        mqttbytes::v5::{
+            Filter, LastWillProperties, PubAckReason, PubCompReason, PublishProperties,
+            RetainForwardRule, SubscribeProperties,
+        },
+    },
+    Outgoing, QoS, TlsConfiguration,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+/// Capacity of the channel of requests to the event loop.
+const REQUEST_CAPACITY: usize = 64;
+
+/// [`Transport`] using the pure Rust `rumqttc` client.
+///
+/// The broker address and client ID are taken from the `rumqttc` options the transport is created
+/// with. The [`ConnectOptions`] are applied to those options every time the transport connects,
+/// credentials given when the transport was created are kept if the options have none.
+///
+/// The transport reconnects itself when the connection is lost only if the options enable
+/// automatic reconnection, otherwise it stays disconnected until connected again. TLS
+/// certificates and keys given as files are read again before every attempt to reconnect.
+///
+/// Requests fail while disconnected. Requests that are not acknowledged when the connection is
+/// lost fail with an error and are not sent again after reconnecting.
+#[derive(Clone)]
+pub struct RumqttcTransport {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    client: RumqttcClient,
+    event_loop: Mutex<Option<EventLoop>>,
+
+    client_id: String,
+    server_uri: String,
+
+    connected: AtomicBool,
+    handler: Mutex<Option<Arc<TransportEventHandler>>>,
+    acks: Mutex<Acks>,
+}
+
+enum RumqttcClient {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+enum EventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl RumqttcTransport {
+    /// Create a transport that connects using MQTT v3.1.1.
+    pub fn new(options: rumqttc::MqttOptions) -> Self {
+        let client_id = options.client_id();
+        let (host, port) = options.broker_address();
+
+        let (client, event_loop) = rumqttc::AsyncClient::new(options, REQUEST_CAPACITY);
+
+        Self::with_client(
+            RumqttcClient::V4(client),
+            EventLoop::V4(Box::new(event_loop)),
+            client_id,
+            format!("tcp://{}:{}", host, port),
+        )
+    }
+
+    /// Create a transport that connects using MQTT v5.
+    pub fn new_v5(options: v5::MqttOptions) -> Self {
+        let client_id = options.client_id();
+        let (host, port) = options.broker_address();
+
+        let (client, event_loop) = v5::AsyncClient::new(options, REQUEST_CAPACITY);
+
+        Self::with_client(
+            RumqttcClient::V5(client),
+            EventLoop::V5(Box::new(event_loop)),
+            client_id,
+            format!("tcp://{}:{}", host, port),
+        )
+    }
+
+    /// Create a transport for a broker URI such as `tcp://localhost:1883`, connecting using
+    /// MQTT v5 if `mqtt_version` is [`MQTT_VERSION_5`] and v3.1.1 otherwise.
+    ///
+    /// The `ssl` and `mqtts` schemes connect using TLS, verifying the broker certificate against
+    /// the platform's trusted certificates unless the options to connect with have a
+    /// [`TlsConfig`].
+    pub fn with_uri(uri: &str, client_id: &str, mqtt_version: u32) -> crate::Result<Self> {
+        let invalid = || crate::Error::RumqttcError(format!("invalid broker URI \"{}\"", uri));
+
+        let (scheme, address) = uri.split_once("://").ok_or_else(invalid)?;
+        let (tls, default_port) = match scheme {
+            "tcp" | "mqtt" => (false, 1883),
+            "ssl" | "mqtts" => (true, 8883),
+            _ => return Err(invalid()),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (address, default_port),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        let transport = match tls {
+            true => rumqttc::Transport::tls_with_config(TlsConfiguration::default()),
+            false => rumqttc::Transport::tcp(),
+        };
+
+        let (client, event_loop) = if mqtt_version == MQTT_VERSION_5 {
+            let mut options = v5::MqttOptions::new(client_id, host, port);
+            options.set_transport(transport);
+
+            let (client, event_loop) = v5::AsyncClient::new(options, REQUEST_CAPACITY);
+            (
+                RumqttcClient::V5(client),
+                EventLoop::V5(Box::new(event_loop)),
+            )
+        } else {
+            let mut options = rumqttc::MqttOptions::new(client_id, host, port);
+            options.set_transport(transport);
+
+            let (client, event_loop) = rumqttc::AsyncClient::new(options, REQUEST_CAPACITY);
+            (
+                RumqttcClient::V4(client),
+                EventLoop::V4(Box::new(event_loop)),
+            )
+        };
+
+        Ok(Self::with_client(
+            client,
+            event_loop,
+            client_id.to_owned(),
+            uri.to_owned(),
+        ))
+    }
+
+    fn with_client(
+        client: RumqttcClient,
+        event_loop: EventLoop,
+        client_id: String,
+        server_uri: String,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                event_loop: Mutex::new(Some(event_loop)),
+
+                client_id,
+                server_uri,
+
+                connected: Default::default(),
+                handler: Default::default(),
+                acks: Default::default(),
+            }),
+        }
+    }
+
+    fn raise(&self, event: TransportEvent) {
+        let handler = self.inner.handler.lock().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(self, event);
+        }
+    }
+
+    /// Make a request to the event loop and wait for it to be acknowledged.
+    fn request(
+        &self,
+        kind: Request,
+        send: impl FnOnce(&RumqttcClient) -> crate::Result<()>,
+    ) -> crate::Result<TransportFuture<()>> {
+        // The lock is held until the request is made so that the event loop cannot see the
+        // request before it is queued, or discard the requests while it is made
+        let mut acks = self.inner.acks.lock().unwrap();
+        if !self.inner.connected.load(Ordering::Relaxed) {
+            return Err(crate::Error::NotConnected);
+        }
+        let rx = acks.queue(kind);
+        if let Err(e) = send(&self.inner.client) {
+            acks.unqueue(kind);
+            return Err(e);
+        }
+
+        Ok(Box::pin(async move {
+            rx.await
+                .map_err(|_| crate::Error::RumqttcError("Request was abandoned".into()))?
+        }))
+    }
+
+    /// Poll the event loop until the transport is dropped, or the connection fails and is not
+    /// reconnected automatically.
+    ///
+    /// `reconnect` is the shortest and longest time to wait between attempts to reconnect. The
+    /// files of the `tls` configuration are read again before every attempt.
+    async fn run(
+        inner: Weak<Inner>,
+        mut event_loop: EventLoop,
+        reconnect: Option<(Duration, Duration)>,
+        tls: Option<TlsConfig>,
+        first_connect: oneshot::Sender<crate::Result<ConnectResponse>>,
+    ) {
+        let mut first_connect = Some(first_connect);
+        let mut wait = Duration::ZERO;
+
+        loop {
+            let event = event_loop.poll().await;
+
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            let this = Self { inner };
+
+            match event {
+                Ok(LoopEvent::ConnAck { session_present }) => {
+                    this.inner.connected.store(true, Ordering::Relaxed);
+                    wait = Duration::ZERO;
+
+                    if let Some(tx) = first_connect.take() {
+                        let _ = tx.send(Ok(ConnectResponse {
+                            server_uri: this.server_uri(),
+                            mqtt_version: this.mqtt_version(),
+                            session_present,
+                        }));
+                    }
+
+                    this.raise(TransportEvent::Connected);
+                }
+                Ok(LoopEvent::Message(msg)) => this.raise(TransportEvent::Message(msg)),
+                Ok(LoopEvent::Disconnect(reason)) => {
+                    this.inner.connected.store(false, Ordering::Relaxed);
+                    this.raise(TransportEvent::Disconnected(reason));
+                }
+                Ok(LoopEvent::Outgoing(Outgoing::Publish(pkid))) => {
+                    this.inner.acks.lock().unwrap().sent(Request::Publish, pkid)
+                }
+                Ok(LoopEvent::Outgoing(Outgoing::Subscribe(pkid))) => this
+                    .inner
+                    .acks
+                    .lock()
+                    .unwrap()
+                    .sent(Request::Subscribe, pkid),
+                Ok(LoopEvent::Outgoing(Outgoing::Unsubscribe(pkid))) => this
+                    .inner
+                    .acks
+                    .lock()
+                    .unwrap()
+                    .sent(Request::Unsubscribe, pkid),
+                Ok(LoopEvent::Ack(kind, pkid, result)) => {
+                    this.inner.acks.lock().unwrap().acked(kind, pkid, result)
+                }
+                Ok(LoopEvent::Outgoing(_)) | Ok(LoopEvent::Other) => {}
+                Err(e) => {
+                    // Packet identifiers are only unique within a connection, so requests that
+                    // were not acknowledged cannot be matched with acknowledgements after it
+                    let was_connected = {
+                        let mut acks = this.inner.acks.lock().unwrap();
+                        event_loop.discard_requests();
+                        acks.fail_all();
+                        this.inner.connected.swap(false, Ordering::Relaxed)
+                    };
+
+                    if let Some(tx) = first_connect.take() {
+                        // Allow connecting to be tried again
+                        *this.inner.event_loop.lock().unwrap() = Some(event_loop);
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+
+                    let Some((min_interval, max_interval)) = reconnect else {
+                        tracing::debug!(error = %e, "Connection error");
+
+                        // Allow connecting again, with new options
+                        *this.inner.event_loop.lock().unwrap() = Some(event_loop);
+                        if was_connected {
+                            this.raise(TransportEvent::ConnectionLost);
+                        }
+                        return;
+                    };
+
+                    if was_connected {
+                        this.raise(TransportEvent::ConnectionLost);
+                    }
+
+                    // Release the transport while waiting, so the task stops if it is dropped
+                    drop(this);
+
+                    tracing::debug!(error = %e, "Connection error, reconnecting");
+                    wait = (wait * 2).max(min_interval).min(max_interval);
+                    tokio::time::sleep(wait).await;
+
+                    if let Some(tls) = &tls {
+                        event_loop.reload_tls(tls);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Transport for RumqttcTransport {
+    fn client_id(&self) -> String {
+        self.inner.client_id.clone()
+    }
+
+    fn server_uri(&self) -> String {
+        self.inner.server_uri.clone()
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        match self.inner.client {
+            RumqttcClient::V4(_) => MQTT_VERSION_3_1_1,
+            RumqttcClient::V5(_) => MQTT_VERSION_5,
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::Relaxed)
+    }
+
+    fn set_event_handler(&self, handler: TransportEventHandler) {
+        *self.inner.handler.lock().unwrap() = Some(Arc::new(handler));
+    }
+
+    fn connect(&self, options: ConnectOptions) -> TransportFuture<ConnectResponse> {
+        let event_loop = {
+            let mut slot = self.inner.event_loop.lock().unwrap();
+            let Some(mut event_loop) = slot.take() else {
+                return Box::pin(async {
+                    Err(crate::Error::RumqttcError("Already connected".into()))
+                });
+            };
+
+            if let Err(e) = event_loop.apply(&options) {
+                *slot = Some(event_loop);
+                return Box::pin(async { Err(e) });
+            }
+            event_loop
+        };
+
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(Self::run(
+            Arc::downgrade(&self.inner),
+            event_loop,
+            options.automatic_reconnect,
+            options.tls.clone(),
+            tx,
+        ));
+
+        Box::pin(async move {
+            rx.await
+                .map_err(|_| crate::Error::RumqttcError("Event loop stopped".into()))?
+        })
+    }
+
+    fn publish(&self, msg: Message) -> crate::Result<TransportFuture<()>> {
+        self.request(Request::Publish, |client| match client {
+            RumqttcClient::V4(client) => client
+                .try_publish(
+                    msg.topic(),
+                    qos(msg.qos()),
+                    msg.retained(),
+                    msg.payload().to_vec(),
+                )
+                .map_err(|e| crate::Error::RumqttcError(e.to_string())),
+            RumqttcClient::V5(client) => client
+                .try_publish_with_properties(
+                    msg.topic(),
+                    qos_v5(msg.qos()),
+                    msg.retained(),
+                    msg.payload().to_vec(),
+                    publish_properties(&msg.properties),
+                )
+                .map_err(|e| crate::Error::RumqttcError(e.to_string())),
+        })
+    }
+
+    fn subscribe(&self, subscription: &Subscription) -> TransportFuture<()> {
+        let topic = subscription.broker_topic();
+
+        let request = self.request(Request::Subscribe, |client| match client {
+            RumqttcClient::V4(client) => client
+                .try_subscribe(topic, qos(subscription.qos()))
+                .map_err(|e| crate::Error::RumqttcError(e.to_string())),
+            RumqttcClient::V5(client) => {
+                let filter = Filter {
+                    path: topic,
+                    qos: qos_v5(subscription.qos()),
+                    nolocal: subscription.no_local(),
+                    preserve_retain: subscription.retain_as_published(),
+                    retain_forward_rule: match subscription.retain_handling() {
+                        RetainHandling::SendOnSubscribe => RetainForwardRule::OnEverySubscribe,
+                        RetainHandling::SendOnNew => RetainForwardRule::OnNewSubscribe,
+                        RetainHandling::DontSend => RetainForwardRule::Never,
+                    },
+                };
+                let props = SubscribeProperties {
+                    id: subscription.subscription_id().map(|id| id as usize),
+                    user_properties: Vec::new(),
+                };
+
+                client
+                    .try_subscribe_many_with_properties([filter], props)
+                    .map_err(|e| crate::Error::RumqttcError(e.to_string()))
+            }
+        });
+
+        Box::pin(async move { request?.await })
+    }
+
+    fn unsubscribe(&self, topic: &str) -> TransportFuture<()> {
+        let request = self.request(Request::Unsubscribe, |client| match client {
+            RumqttcClient::V4(client) => client
+                .try_unsubscribe(topic)
+                .map_err(|e| crate::Error::RumqttcError(e.to_string())),
+            RumqttcClient::V5(client) => client
+                .try_unsubscribe(topic)
+                .map_err(|e| crate::Error::RumqttcError(e.to_string())),
+        });
+
+        Box::pin(async move { request?.await })
+    }
+}
+
+/// Kind of request that is acknowledged by the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Request {
+    Publish,
+    Subscribe,
+    Unsubscribe,
+}
+
+/// Requests that are waiting to be acknowledged.
+///
+/// Requests are sent by the event loop in the order they are made, so each packet identifier
+/// reported by the event loop belongs to the oldest request of its kind that has none yet.
+#[derive(Default)]
+struct Acks {
+    queued: HashMap<Request, VecDeque<oneshot::Sender<crate::Result<()>>>>,
+    inflight: HashMap<(Request, u16), oneshot::Sender<crate::Result<()>>>,
+}
+
+impl Acks {
+    fn queue(&mut self, kind: Request) -> oneshot::Receiver<crate::Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        self.queued.entry(kind).or_default().push_back(tx);
+        rx
+    }
+
+    fn unqueue(&mut self, kind: Request) {
+        self.queued.entry(kind).or_default().pop_back();
+    }
+
+    /// The event loop sent a request with a packet identifier, which is `0` for QoS 0 publishes.
+    fn sent(&mut self, kind: Request, pkid: u16) {
+        if let Some(tx) = self.queued.entry(kind).or_default().pop_front() {
+            if pkid == 0 {
+                let _ = tx.send(Ok(()));
+            } else {
+                self.inflight.insert((kind, pkid), tx);
+            }
+        }
+    }
+
+    fn acked(&mut self, kind: Request, pkid: u16, result: crate::Result<()>) {
+        if let Some(tx) = self.inflight.remove(&(kind, pkid)) {
+            let _ = tx.send(result);
+        }
+    }
+
+    /// Fail all the requests, when the connection is lost.
+    fn fail_all(&mut self) {
+        let queued = self.queued.drain().flat_map(|(_, queue)| queue);
+        let inflight = self.inflight.drain().map(|(_, tx)| tx);
+        for tx in queued.chain(inflight) {
+            let _ = tx.send(Err(crate::Error::RumqttcError(
+                "Connection lost before the request was acknowledged".into(),
+            )));
+        }
+    }
+}
+
+/// Event loop activity, independent of the MQTT version.
+enum LoopEvent {
+    ConnAck { session_present: bool },
+    Message(Message),
+    Disconnect(ReasonCode),
+    Outgoing(Outgoing),
+    Ack(Request, u16, crate::Result<()>),
+    Other,
+}
+
+impl EventLoop {
+    async fn poll(&mut self) -> crate::Result<LoopEvent> {
+        let rejected = |what: &str| Err(crate::Error::RumqttcError(format!("{} rejected", what)));
+
+        match self {
+            Self::V4(event_loop) => {
+                use rumqttc::{Event, Packet, SubscribeReasonCode};
+
+                let event = event_loop
+                    .poll()
+                    .await
+                    .map_err(|e| crate::Error::RumqttcError(e.to_string()))?;
+
+                Ok(match event {
+                    Event::Incoming(Packet::ConnAck(ack)) => LoopEvent::ConnAck {
+                        session_present: ack.session_present,
+                    },
+                    Event::Incoming(Packet::Publish(publish)) => LoopEvent::Message(Message {
+                        topic: publish.topic,
+                        payload: publish.payload.to_vec(),
+                        qos: publish.qos as i32,
+                        retained: publish.retain,
+                        duplicate: publish.dup,
+                        properties: Default::default(),
+                    }),
+                    Event::Incoming(Packet::PubAck(ack)) => {
+                        LoopEvent::Ack(Request::Publish, ack.pkid, Ok(()))
+                    }
+                    Event::Incoming(Packet::PubComp(ack)) => {
+                        LoopEvent::Ack(Request::Publish, ack.pkid, Ok(()))
+                    }
+                    Event::Incoming(Packet::SubAck(ack)) => {
+                        let result = match ack.return_codes.contains(&SubscribeReasonCode::Failure)
+                        {
+                            true => rejected("Subscription"),
+                            false => Ok(()),
+                        };
+                        LoopEvent::Ack(Request::Subscribe, ack.pkid, result)
+                    }
+                    Event::Incoming(Packet::UnsubAck(ack)) => {
+                        LoopEvent::Ack(Request::Unsubscribe, ack.pkid, Ok(()))
+                    }
+                    Event::Outgoing(outgoing) => LoopEvent::Outgoing(outgoing),
+                    Event::Incoming(_) => LoopEvent::Other,
+                })
+            }
+            Self::V5(event_loop) => {
+                use rumqttc::v5::{
+                    mqttbytes::v5::{Packet, SubscribeReasonCode, UnsubAckReason},
+                    Event,
+                };
+
+                let event = event_loop
+                    .poll()
+                    .await
+                    .map_err(|e| crate::Error::RumqttcError(e.to_string()))?;
+
+                Ok(match event {
+                    Event::Incoming(Packet::ConnAck(ack)) => LoopEvent::ConnAck {
+                        session_present: ack.session_present,
+                    },
+                    Event::Incoming(Packet::Publish(publish)) => LoopEvent::Message(Message {
+                        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                        payload: publish.payload.to_vec(),
+                        qos: publish.qos as i32,
+                        retained: publish.retain,
+                        duplicate: publish.dup,
+                        properties: Box::new(
+                            publish
+                                .properties
+                                .map(message_properties)
+                                .unwrap_or_default(),
+                        ),
+                    }),
+                    Event::Incoming(Packet::PubAck(ack)) => {
+                        let result = match ack.reason {
+                            PubAckReason::Success | PubAckReason::NoMatchingSubscribers => Ok(()),
+                            _ => rejected("Publish"),
+                        };
+                        LoopEvent::Ack(Request::Publish, ack.pkid, result)
+                    }
+                    Event::Incoming(Packet::PubComp(ack)) => {
+                        let result = match ack.reason {
+                            PubCompReason::Success => Ok(()),
+                            _ => rejected("Publish"),
+                        };
+                        LoopEvent::Ack(Request::Publish, ack.pkid, result)
+                    }
+                    Event::Incoming(Packet::SubAck(ack)) => {
+                        let result = match ack
+                            .return_codes
+                            .iter()
+                            .all(|c| matches!(c, SubscribeReasonCode::Success(_)))
+                        {
+                            true => Ok(()),
+                            false => rejected("Subscription"),
+                        };
+                        LoopEvent::Ack(Request::Subscribe, ack.pkid, result)
+                    }
+                    Event::Incoming(Packet::UnsubAck(ack)) => {
+                        let result = match ack.reasons.iter().all(|r| {
+                            matches!(
+                                r,
+                                UnsubAckReason::Success | UnsubAckReason::NoSubscriptionExisted
+                            )
+                        }) {
+                            true => Ok(()),
+                            false => rejected("Unsubscribe"),
+                        };
+                        LoopEvent::Ack(Request::Unsubscribe, ack.pkid, result)
+                    }
+                    Event::Incoming(Packet::Disconnect(disconnect)) => {
+                        LoopEvent::Disconnect(ReasonCode(disconnect.reason_code as u8))
+                    }
+                    Event::Outgoing(outgoing) => LoopEvent::Outgoing(outgoing),
+                    Event::Incoming(_) => LoopEvent::Other,
+                })
+            }
+        }
+    }
+}
+
+impl EventLoop {
+    /// Discard the requests that were made but not acknowledged, so they are not sent after
+    /// reconnecting.
+    ///
+    /// Releases of QoS 2 messages are kept, the broker is waiting for them.
+    fn discard_requests(&mut self) {
+        match self {
+            Self::V4(event_loop) => {
+                event_loop.clean();
+                event_loop
+                    .pending
+                    .retain(|r| matches!(r, rumqttc::Request::PubRel(_)));
+            }
+            Self::V5(event_loop) => {
+                event_loop.clean();
+                event_loop
+                    .pending
+                    .retain(|r| matches!(r, v5::Request::PubRel(_)));
+            }
+        }
+    }
+
+    /// Read the certificates and keys of the TLS configuration again, so that renewed ones are
+    /// used for the next connection. The current configuration is kept if they cannot be read.
+    fn reload_tls(&mut self, tls: &TlsConfig) {
+        let transport = match tls_configuration(tls) {
+            Ok(config) => rumqttc::Transport::tls_with_config(config),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to reload the TLS configuration");
+                return;
+            }
+        };
+
+        match self {
+            Self::V4(event_loop) => {
+                event_loop.mqtt_options.set_transport(transport);
+            }
+            Self::V5(event_loop) => {
+                event_loop.options.set_transport(transport);
+            }
+        }
+    }
+
+    /// Apply the options to connect with to the `rumqttc` options of the event loop.
+    ///
+    /// The options are only changed if all of them can be applied.
+    fn apply(&mut self, options: &ConnectOptions) -> crate::Result<()> {
+        let unsupported = |what: String| {
+            Err(crate::Error::RumqttcError(format!(
+                "{} is not supported by the rumqttc transport",
+                what
+            )))
+        };
+
+        let version = match self {
+            Self::V4(_) => MQTT_VERSION_3_1_1,
+            Self::V5(_) => MQTT_VERSION_5,
+        };
+        if options.mqtt_version != MQTT_VERSION_DEFAULT && options.mqtt_version != version {
+            return unsupported(format!(
+                "Connecting with MQTT version {} using a transport created for version {}",
+                options.mqtt_version, version
+            ));
+        }
+
+        let credentials = &options.credentials;
+        let credentials =
+            (credentials.username.is_some() || credentials.password.is_some()).then(|| {
+                (
+                    credentials.username.clone().unwrap_or_default(),
+                    credentials.password.clone().unwrap_or_default(),
+                )
+            });
+
+        let transport = options
+            .tls
+            .as_ref()
+            .map(tls_configuration)
+            .transpose()?
+            .map(rumqttc::Transport::tls_with_config);
+
+        match self {
+            Self::V4(event_loop) => {
+                let keep_alive = options.keep_alive;
+                if !keep_alive.is_zero() && keep_alive < Duration::from_secs(1) {
+                    return unsupported(format!("A keep alive interval of {:?}", keep_alive));
+                }
+
+                let mut mqtt_options = event_loop.mqtt_options.clone();
+                if !options.clean_session && mqtt_options.client_id().is_empty() {
+                    return unsupported("Keeping the session without a client ID".into());
+                }
+
+                mqtt_options
+                    .set_keep_alive(keep_alive)
+                    .set_clean_session(options.clean_session);
+                if let Some((username, password)) = credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(will) = &options.will_message {
+                    mqtt_options.set_last_will(rumqttc::LastWill::new(
+                        will.topic(),
+                        will.payload(),
+                        qos(will.qos()),
+                        will.retained(),
+                    ));
+                }
+                if let Some(transport) = transport {
+                    mqtt_options.set_transport(transport);
+                }
+
+                event_loop.mqtt_options = mqtt_options;
+            }
+            Self::V5(event_loop) => {
+                let keep_alive = options.keep_alive;
+                if keep_alive < Duration::from_secs(5) {
+                    return unsupported(format!("A keep alive interval of {:?}", keep_alive));
+                }
+
+                let mut mqtt_options = event_loop.options.clone();
+                mqtt_options
+                    .set_keep_alive(keep_alive)
+                    .set_clean_start(options.clean_session);
+                if let Some((username, password)) = credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(will) = &options.will_message {
+                    mqtt_options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                        will.topic(),
+                        will.payload(),
+                        qos_v5(will.qos()),
+                        will.retained(),
+                        Some(will_properties(&will.properties)),
+                    ));
+                }
+                if let Some(transport) = transport {
+                    mqtt_options.set_transport(transport);
+                }
+
+                event_loop.options = mqtt_options;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Convert the TLS configuration.
+fn tls_configuration(tls: &TlsConfig) -> crate::Result<TlsConfiguration> {
+    tls.validate()?;
+
+    let unsupported = |what: &str| {
+        Err(crate::Error::InvalidTlsConfig(format!(
+            "{} is not supported by the rumqttc transport",
+            what
+        )))
+    };
+    if tls.insecure_skip_verify {
+        return unsupported("Skipping verification of the broker certificate");
+    }
+    if tls.client_key_password.is_some() {
+        return unsupported("An encrypted client key");
+    }
+
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((cert.read()?.into_bytes(), key.read()?.into_bytes())),
+        (Some(cert), None) => {
+            // The certificate also contains the key
+            let pem = cert.read()?.into_bytes();
+            Some((pem.clone(), pem))
+        }
+        (None, _) => None,
+    };
+
+    let alpn = (!tls.alpn_protocols.is_empty()).then(|| {
+        tls.alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect()
+    });
+
+    match &tls.ca {
+        Some(ca) => Ok(TlsConfiguration::Simple {
+            ca: ca.read()?.into_bytes(),
+            alpn,
+            client_auth,
+        }),
+        None if client_auth.is_none() && alpn.is_none() => Ok(TlsConfiguration::default()),
+        None => unsupported("A client certificate or ALPN without a CA"),
+    }
+}
+
+fn qos(qos: i32) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+fn qos_v5(qos: i32) -> v5::mqttbytes::QoS {
+    match qos {
+        0 => v5::mqttbytes::QoS::AtMostOnce,
+        1 => v5::mqttbytes::QoS::AtLeastOnce,
+        _ => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// Convert the properties of an outgoing message.
+fn publish_properties(props: &MessageProperties) -> PublishProperties {
+    PublishProperties {
+        payload_format_indicator: props.payload_format.map(|format| match format {
+            PayloadFormat::Unspecified => 0,
+            PayloadFormat::Utf8 => 1,
+        }),
+        message_expiry_interval: props
+            .message_expiry_interval
+            .map(|interval| u32::try_from(interval.as_secs()).unwrap_or(u32::MAX)),
+        topic_alias: props.topic_alias,
+        response_topic: props.response_topic.clone(),
+        correlation_data: props.correlation_data.clone().map(Into::into),
+        user_properties: props.user_properties.clone(),
+        subscription_identifiers: Vec::new(),
+        content_type: props.content_type.clone(),
+    }
+}
+
+/// Convert the properties of a will message.
+fn will_properties(props: &MessageProperties) -> LastWillProperties {
+    let props = publish_properties(props);
+
+    LastWillProperties {
+        delay_interval: None,
+        payload_format_indicator: props.payload_format_indicator,
+        message_expiry_interval: props.message_expiry_interval,
+        content_type: props.content_type,
+        response_topic: props.response_topic,
+        correlation_data: props.correlation_data,
+        user_properties: props.user_properties,
+    }
+}
+
+/// Convert the properties of an incoming message.
+fn message_properties(props: PublishProperties) -> MessageProperties {
+    MessageProperties {
+        content_type: props.content_type,
+        message_expiry_interval: props
+            .message_expiry_interval
+            .map(|secs| Duration::from_secs(secs.into())),
+        user_properties: props.user_properties,
+        response_topic: props.response_topic,
+        correlation_data: props.correlation_data.map(|data| data.to_vec()),
+        payload_format: props.payload_format_indicator.map(|v| match v {
+            1 => PayloadFormat::Utf8,
+            _ => PayloadFormat::Unspecified,
+        }),
+        topic_alias: props.topic_alias,
+        subscription_identifiers: props
+            .subscription_identifiers
+            .into_iter()
+            .map(|id| id as i32)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lost() -> crate::Result<()> {
+        Err(crate::Error::RumqttcError("lost".into()))
+    }
+
+    #[test]
+    fn acks() {
+        let mut acks = Acks::default();
+        let mut first = acks.queue(Request::Publish);
+        let mut second = acks.queue(Request::Publish);
+        let mut subscribe = acks.queue(Request::Subscribe);
+
+        acks.sent(Request::Publish, 1);
+        acks.sent(Request::Subscribe, 1);
+        acks.sent(Request::Publish, 2);
+
+        // Acknowledgements are matched by kind and packet identifier, in any order
+        acks.acked(Request::Publish, 2, Ok(()));
+        assert!(second.try_recv().unwrap().is_ok());
+        assert!(first.try_recv().is_err());
+
+        acks.acked(Request::Subscribe, 1, lost());
+        assert!(subscribe.try_recv().unwrap().is_err());
+
+        acks.acked(Request::Publish, 1, Ok(()));
+        assert!(first.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn qos_0() {
+        let mut acks = Acks::default();
+        let mut rx = acks.queue(Request::Publish);
+
+        acks.sent(Request::Publish, 0);
+        assert!(rx.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn unqueue() {
+        let mut acks = Acks::default();
+        let mut first = acks.queue(Request::Publish);
+        let mut second = acks.queue(Request::Publish);
+        acks.unqueue(Request::Publish);
+
+        // The dropped request fails, the other one is still matched
+        assert!(second.try_recv().is_err());
+        acks.sent(Request::Publish, 1);
+        acks.acked(Request::Publish, 1, Ok(()));
+        assert!(first.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn connection_lost() {
+        let mut acks = Acks::default();
+        let mut inflight = acks.queue(Request::Subscribe);
+        let mut queued = acks.queue(Request::Subscribe);
+        acks.sent(Request::Subscribe, 1);
+
+        acks.fail_all();
+        assert!(inflight.try_recv().unwrap().is_err());
+        assert!(queued.try_recv().unwrap().is_err());
+
+        // Packet identifiers are reused after reconnecting
+        let mut rx = acks.queue(Request::Subscribe);
+        acks.sent(Request::Subscribe, 1);
+        acks.acked(Request::Subscribe, 1, Ok(()));
+        assert!(rx.try_recv().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn not_connected() {
+        let transport = RumqttcTransport::with_uri("tcp://localhost:1883", "client", 4).unwrap();
+        let result = transport.publish(Message::new("a", "", 0));
+        assert!(matches!(result, Err(crate::Error::NotConnected)));
+        let subscription = crate::SubscriptionBuilder::default()
+            .topic("a".to_owned())
+            .build()
+            .unwrap();
+        assert!(transport.subscribe(&subscription).await.is_err());
+    }
+
+    #[test]
+    fn reload_tls() {
+        let path = std::env::temp_dir().join(format!("rumqttc-reload-{}.pem", std::process::id()));
+        let pem = |data: &str| {
+            format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                data
+            )
+        };
+        let ca = |event_loop: &EventLoop| match event_loop {
+            EventLoop::V4(event_loop) => match event_loop.mqtt_options.transport() {
+                rumqttc::Transport::Tls(TlsConfiguration::Simple { ca, .. }) => {
+                    String::from_utf8(ca).unwrap()
+                }
+                _ => panic!("TLS should be configured"),
+            },
+            EventLoop::V5(_) => unreachable!(),
+        };
+
+        let tls = crate::TlsConfigBuilder::default()
+            .ca(crate::PemSource::File(path.clone()))
+            .build()
+            .unwrap();
+        let mut event_loop = EventLoop::V4(Box::new(rumqttc::EventLoop::new(
+            rumqttc::MqttOptions::new("client", "localhost", 8883),
+            REQUEST_CAPACITY,
+        )));
+
+        std::fs::write(&path, pem("old")).unwrap();
+        event_loop.reload_tls(&tls);
+        assert_eq!(ca(&event_loop), pem("old"));
+
+        // A renewed certificate is used for the next connection
+        std::fs::write(&path, pem("new")).unwrap();
+        event_loop.reload_tls(&tls);
+        assert_eq!(ca(&event_loop), pem("new"));
+
+        // The current configuration is kept if the file cannot be read
+        std::fs::remove_file(&path).unwrap();
+        event_loop.reload_tls(&tls);
+        assert_eq!(ca(&event_loop), pem("new"));
+    }
+
+    #[test]
+    fn properties() {
+        let props = crate::MessageBuilder::new("a", "")
+            .content_type("text/plain")
+            .message_expiry_interval(Duration::from_secs(60))
+            .user_property("key", "value")
+            .response_topic("response/a")
+            .correlation_data("1234")
+            .payload_format(PayloadFormat::Utf8)
+            .topic_alias(3)
+            .build()
+            .properties
+            .clone();
+
+        assert_eq!(message_properties(publish_properties(&props)), *props);
+
+        // Subscription identifiers are set by the broker, so are only received
+        let received = PublishProperties {
+            subscription_identifiers: vec![1, 5],
+            ..Default::default()
+        };
+        assert_eq!(
+            message_properties(received).subscription_identifiers,
+            [1, 5]
+        );
+        let sent = MessageProperties {
+            subscription_identifiers: vec![1],
+            ..Default::default()
+        };
+        assert!(publish_properties(&sent)
+            .subscription_identifiers
+            .is_empty());
+    }
+}